@@ -7,12 +7,14 @@ extern crate shrinkwraprs;
 
 use dbus::{BusType, Connection, Message};
 use std::collections::HashMap;
+use system76_firmware::changelog::version_cmp;
 
 pub const DBUS_DEST: &str = "com.system76.FirmwareDaemon";
 pub const DBUS_IFACE: &str = DBUS_DEST;
 pub const DBUS_PATH: &str = "/com/system76/FirmwareDaemon";
 
 pub const METHOD_BIOS: &str = "Bios";
+pub const METHOD_BIOS_DATE: &str = "BiosDate";
 pub const METHOD_DOWNLOAD: &str = "Download";
 pub const METHOD_EC: &str = "EmbeddedController";
 pub const METHOD_FIRMWARE_ID: &str = "FirmwareId";
@@ -44,6 +46,11 @@ pub enum Error {
 }
 
 /// DBus client connection for interacting with the system76-firmware daemon.
+///
+/// The underlying DBus connection is neither `Send` nor `Sync`, so neither is
+/// the client. Open a client on each thread that calls the daemon, as the
+/// daemon serializes the calls it receives anyway. The values returned by the
+/// client are plain data and may be shared freely.
 pub struct Client(Connection);
 
 impl Client {
@@ -55,16 +62,29 @@ impl Client {
 
     /// Retrieves information about the BIOS currently installed on the system.
     pub fn bios(&self) -> Result<BiosInfo, Error> {
-        self.call_method(METHOD_BIOS, |m| m)?
+        let (model, version) = self.call_method(METHOD_BIOS, |m| m)?
             .read2::<String, String>()
-            .map_err(|why| Error::ArgumentMismatch(METHOD_BIOS, why))
-            .map(|(model, version)| BiosInfo {
-                model: model.into(),
-                version: version.into(),
-            })
+            .map_err(|why| Error::ArgumentMismatch(METHOD_BIOS, why))?;
+
+        Ok(BiosInfo {
+            model: model.into(),
+            version: version.into(),
+            // Older daemons do not have the method
+            date: self.bios_date().ok(),
+        })
+    }
+
+    /// Retrieves the release date of the installed BIOS, as `YYYY-MM-DD`.
+    pub fn bios_date(&self) -> Result<Box<str>, Error> {
+        self.call_method(METHOD_BIOS_DATE, |m| m)?
+            .read1::<String>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_BIOS_DATE, why))
+            .map(Box::from)
     }
 
-    /// Downloads the latest firmware metadata for the system..
+    /// Downloads the latest firmware metadata for the system. The versions of
+    /// the changelog are sorted newest first, as the daemon sends the
+    /// changelog as it was published.
     pub fn download(&self) -> Result<SystemInfo, Error> {
         let (digest, changelog) = self
             .call_method(METHOD_DOWNLOAD, |m| m)?
@@ -73,9 +93,12 @@ impl Client {
 
         serde_json::from_str(changelog.as_str())
             .map_err(move |why| Error::Changelog(changelog.into(), why))
-            .map(move |changelog| SystemInfo {
-                digest: Digest(digest.into()),
-                changelog: changelog,
+            .map(move |mut changelog: Changelog| {
+                changelog.versions.sort_by(|a, b| version_cmp(&b.bios, &a.bios));
+                SystemInfo {
+                    digest: Digest(digest.into()),
+                    changelog: changelog,
+                }
             })
     }
 
@@ -180,6 +203,8 @@ impl Client {
 pub struct BiosInfo {
     pub model: Box<str>,
     pub version: Box<str>,
+    /// The release date as `YYYY-MM-DD`, if the daemon could read it.
+    pub date: Option<Box<str>>,
 }
 
 /// Changelog containing details about each version of firmware.
@@ -198,6 +223,9 @@ pub struct Version {
     pub me_cr: bool,
     pub me_hap: bool,
     pub me: Option<Box<str>>,
+    /// The battery must be disconnected, or the machine reset, after flashing.
+    #[serde(default)]
+    pub requires_battery_reset: bool,
 }
 
 /// Signature of the firmware that can be installed on the system.
@@ -29,7 +29,7 @@ fn daemon() -> Result<(), String> {
     //TODO: allow override with parameter
     let efi_dir = "/boot";
 
-    let in_whitelist = bios().ok().map_or(false, |(model, _)| model_is_whitelisted(&*model));
+    let in_whitelist = current_model().ok().map_or(false, |model| model_is_whitelisted(&*model));
 
     let c = Connection::get_private(BusType::System).map_err(err_str)?;
     c.register_name(DBUS_DEST, NameFlag::ReplaceExisting as u32)
@@ -61,6 +61,26 @@ fn daemon() -> Result<(), String> {
                     .outarg::<&str, _>("model")
                     .outarg::<&str, _>("version"),
                 )
+                .add_m(
+                    f.method(METHOD_BIOS_DATE, (), move |m| {
+                        eprintln!("BiosDate");
+                        if !in_whitelist {
+                            return Err(MethodErr::failed(&"product is not in whitelist"));
+                        }
+
+                        match bios_date() {
+                            Ok(bios_date) => {
+                                let mret = m.msg.method_return().append1(bios_date);
+                                Ok(vec![mret])
+                            }
+                            Err(err) => {
+                                eprintln!("{}", err);
+                                Err(MethodErr::failed(&err))
+                            }
+                        }
+                    })
+                    .outarg::<&str, _>("date"),
+                )
                 .add_m(
                     f.method(METHOD_EC, (), move |m| {
                         let primary = m.msg.read1()?;
@@ -115,7 +135,7 @@ fn daemon() -> Result<(), String> {
                         }
                         match firmware_id() {
                             Ok(id) => {
-                                let mret = m.msg.method_return().append1(id);
+                                let mret = m.msg.method_return().append1(id.to_string());
                                 Ok(vec![mret])
                             }
                             Err(err) => {
@@ -220,7 +240,7 @@ fn daemon() -> Result<(), String> {
                         let digest = m.msg.read1()?;
                         eprintln!("ThelioIoUpdate({})", digest);
                         match thelio_io_update(digest) {
-                            Ok(()) => {
+                            Ok(_result) => {
                                 let mret = m.msg.method_return();
                                 Ok(vec![mret])
                             }
@@ -4,5 +4,5 @@ use system76_firmware::{thelio_io_download, thelio_io_update};
 
 fn main() -> Result<(), String> {
     let (digest, _revision) = thelio_io_download()?;
-    thelio_io_update(&digest)
+    thelio_io_update(&digest).map(|_result| ())
 }
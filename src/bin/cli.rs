@@ -20,11 +20,45 @@ fn tool() -> Result<(), String> {
     //TODO: allow override with parameter
     let efi_dir = "/boot";
 
-    let usage = "subcommands:\n  schedule\n  unschedule\n  thelio-io";
+    let usage = "subcommands:\n  ec-backup --pause-ec <file>\n  firmware-id\n  schedule [digest]\n  self-test\n  unschedule\n  thelio-io";
     match env::args().nth(1) {
         Some(arg) => match arg.as_str() {
+            "ec-backup" => {
+                // Reading the EC pauses fan and keyboard control, so ask for it by name
+                if env::args().nth(2).as_deref() != Some("--pause-ec") {
+                    return Err(format!(
+                        "ec-backup stops the EC while it is read, pass --pause-ec to continue\n{}",
+                        usage
+                    ));
+                }
+                let file = env::args().nth(3).ok_or(format!("no file provided\n{}", usage))?;
+                match ec_backup(true, &file) {
+                    Ok(()) => Ok(()),
+                    Err(err) => Err(format!("failed to back up EC: {}", err))
+                }
+            },
+            "firmware-id" => {
+                let report = firmware_id_diagnostic();
+                match report.bios_model {
+                    Ok(model) => println!("bios model: {}", model),
+                    Err(err) => println!("bios model: error: {}", err),
+                }
+                match report.ec_project {
+                    Ok(project) => println!("ec project: {}", project),
+                    Err(err) => println!("ec project: error: {}", err),
+                }
+                match report.firmware_id {
+                    Some(id) => println!("firmware id: {}", id),
+                    None => println!("firmware id: unknown"),
+                }
+                Ok(())
+            },
             "schedule" => {
-                let (digest, _changelog) = match download() {
+                let downloaded = match env::args().nth(2) {
+                    Some(pinned) => download_pinned(&pinned),
+                    None => download(),
+                };
+                let (digest, _changelog) = match downloaded {
                     Ok(ok) => ok,
                     Err(err) => return Err(format!("failed to download: {}", err))
                 };
@@ -34,6 +68,24 @@ fn tool() -> Result<(), String> {
                     Err(err) => Err(format!("failed to schedule: {}", err))
                 }
             },
+            "self-test" => {
+                let mut failed = false;
+                for check in self_test(efi_dir) {
+                    match check.result {
+                        Ok(ok) => println!("{}: ok: {}", check.name, ok),
+                        Err(err) => {
+                            println!("{}: error: {}", check.name, err);
+                            failed = true;
+                        }
+                    }
+                }
+
+                if failed {
+                    Err("self test failed".to_string())
+                } else {
+                    Ok(())
+                }
+            },
             "unschedule" => {
                 match unschedule(efi_dir) {
                     Ok(()) => Ok(()),
@@ -47,7 +99,7 @@ fn tool() -> Result<(), String> {
                 };
 
                 match thelio_io_update(&digest) {
-                    Ok(()) => Ok(()),
+                    Ok(_result) => Ok(()),
                     Err(err) => Err(format!("failed to update: {}", err))
                 }
             },
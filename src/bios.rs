@@ -1,15 +1,15 @@
 use util;
 
 pub fn bios() -> Result<(String, String), String> {
-    let bios_model = match util::read_string("/sys/class/dmi/id/product_version") {
-        Ok(ok) => ok.trim().to_string(),
+    let bios_model = match util::read_dmi("/sys/class/dmi/id/product_version") {
+        Ok(ok) => ok,
         Err(err) => {
             return Err(format!("failed to read BIOS model: {}", err));
         }
     };
 
-    let bios_version = match util::read_string("/sys/class/dmi/id/bios_version") {
-        Ok(ok) => ok.trim().to_string(),
+    let bios_version = match util::read_dmi("/sys/class/dmi/id/bios_version") {
+        Ok(ok) => ok,
         Err(err) => {
             return Err(format!("failed to read BIOS version: {}", err));
         }
@@ -17,3 +17,92 @@ pub fn bios() -> Result<(String, String), String> {
 
     Ok((bios_model, bios_version))
 }
+
+// Parses the DMI `MM/DD/YYYY` format into an ISO 8601 `YYYY-MM-DD` date
+fn parse_date(date: &str) -> Option<String> {
+    let mut parts = date.split('/');
+    let month = parts.next()?.parse::<u8>().ok()?;
+    let day = parts.next()?.parse::<u8>().ok()?;
+    let year = parts.next()?;
+    if parts.next().is_some() || year.len() != 4 {
+        return None;
+    }
+    let year = year.parse::<u16>().ok()?;
+
+    if ! (1..=12).contains(&month) || ! (1..=31).contains(&day) {
+        return None;
+    }
+
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+/// Reads the BIOS release date, as an ISO 8601 `YYYY-MM-DD` date.
+pub fn bios_date() -> Result<String, String> {
+    let bios_date = match util::read_dmi("/sys/class/dmi/id/bios_date") {
+        Ok(ok) => ok,
+        Err(err) => {
+            return Err(format!("failed to read BIOS date: {}", err));
+        }
+    };
+
+    parse_date(&bios_date).ok_or(format!("failed to parse BIOS date: {:?}", bios_date))
+}
+
+// Substrings of the DMI vendor and product names reported by common hypervisors
+const HYPERVISORS: &[(&str, &str)] = &[
+    ("QEMU", "QEMU"),
+    ("KVM", "KVM"),
+    ("VMware", "VMware"),
+    ("VirtualBox", "VirtualBox"),
+    ("innotek", "VirtualBox"),
+    ("Xen", "Xen"),
+    ("Virtual Machine", "Hyper-V"),
+    ("Parallels", "Parallels"),
+    ("Bochs", "Bochs"),
+];
+
+// Matches DMI vendor and product names against known hypervisors
+fn detect_hypervisor(sys_vendor: &str, product_name: &str) -> Option<&'static str> {
+    HYPERVISORS.iter().find(|&&(needle, _)| {
+        sys_vendor.contains(needle) || product_name.contains(needle)
+    }).map(|&(_, name)| name)
+}
+
+/// Detects whether this is a virtual machine, returning the hypervisor name.
+/// The DMI data of a virtual machine describes no real firmware, so there is
+/// nothing to update.
+pub fn virtual_machine() -> Option<&'static str> {
+    let sys_vendor = util::read_dmi("/sys/class/dmi/id/sys_vendor").unwrap_or_default();
+    let product_name = util::read_dmi("/sys/class/dmi/id/product_name").unwrap_or_default();
+    detect_hypervisor(&sys_vendor, &product_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_date_formats() {
+        assert_eq!(parse_date("03/14/2020"), Some("2020-03-14".to_string()));
+        assert_eq!(parse_date("3/4/2020"), Some("2020-03-04".to_string()));
+    }
+
+    #[test]
+    fn parse_date_rejects_malformed() {
+        assert_eq!(parse_date(""), None);
+        assert_eq!(parse_date("2020-03-14"), None);
+        assert_eq!(parse_date("13/14/2020"), None);
+        assert_eq!(parse_date("03/32/2020"), None);
+        assert_eq!(parse_date("03/14/20"), None);
+        assert_eq!(parse_date("03/14/2020/1"), None);
+    }
+
+    #[test]
+    fn detect_hypervisors() {
+        assert_eq!(detect_hypervisor("QEMU", "Standard PC (Q35 + ICH9, 2009)"), Some("QEMU"));
+        assert_eq!(detect_hypervisor("innotek GmbH", "VirtualBox"), Some("VirtualBox"));
+        assert_eq!(detect_hypervisor("Microsoft Corporation", "Virtual Machine"), Some("Hyper-V"));
+        assert_eq!(detect_hypervisor("System76", "Galago Pro"), None);
+        assert_eq!(detect_hypervisor("", ""), None);
+    }
+}
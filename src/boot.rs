@@ -1,10 +1,112 @@
-use std::{fs, process};
-use std::path::Path;
+use libc;
+use std::{fs, io, process};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
 use mount;
+use StatusReporter;
 use util;
 
-pub fn set_next_boot(efi_dir: &str) -> Result<(), String> {
+/// The boot entry number used for the firmware updater
+pub const BOOT_NUM: u16 = 0x1776;
+
+/// The `BootNext` variable, under the EFI global variable GUID
+const BOOT_NEXT_VAR: &str = "/sys/firmware/efi/efivars/BootNext-8be4df61-93ca-11d0-aa0d-00e098032b8c";
+
+/// The `SecureBoot` variable, under the EFI global variable GUID
+const SECURE_BOOT_VAR: &str = "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d0-aa0d-00e098032b8c";
+
+/// The boot environment that scheduling depends on, detected in one place.
+#[derive(Clone, Debug)]
+pub struct UefiEnvironment {
+    /// The system was booted with UEFI rather than legacy BIOS.
+    pub booted_uefi: bool,
+    /// Whether efivarfs is mounted read-write, or `None` if it is not mounted.
+    pub efivars_writable: Option<bool>,
+    /// Whether Secure Boot is enabled, or `None` if it cannot be read.
+    pub secure_boot: Option<bool>,
+    /// The device mounted at the ESP directory, if it is a mount point.
+    pub esp_mount: Option<PathBuf>,
+}
+
+impl UefiEnvironment {
+    pub fn detect(efi_dir: &str) -> UefiEnvironment {
+        let mounts = mount::Mount::all().unwrap_or_default();
+        let find_mount = |dest: &str| mounts.iter().find(|mount| mount.dest.to_str() == Some(dest));
+
+        let efivars_writable = find_mount(EFIVARS).map(|mount| {
+            mount.options.to_str().map_or(false, |options| options.split(',').any(|x| x == "rw"))
+        });
+
+        // efivarfs prefixes the value with four bytes of attributes
+        let secure_boot = fs::read(SECURE_BOOT_VAR).ok().and_then(|data| {
+            data.get(4).map(|&value| value == 1)
+        });
+
+        UefiEnvironment {
+            booted_uefi: Path::new("/sys/firmware/efi").exists(),
+            efivars_writable,
+            secure_boot,
+            esp_mount: find_mount(efi_dir).map(|mount| PathBuf::from(&mount.source)),
+        }
+    }
+}
+
+/// The directory efivarfs is mounted at
+const EFIVARS: &str = "/sys/firmware/efi/efivars";
+
+/// Checks that EFI variables can be written, as setting `BootNext` requires,
+/// without writing one: efivarfs has to be mounted read-write, and an existing
+/// variable has to pass `access(W_OK)`. efivarfs marks variables that the
+/// firmware depends on as immutable, which `access` reports as `EPERM`, and
+/// efibootmgr clears that flag before writing, so it does not count against
+/// writing. This requires root.
+pub fn efivars_writable() -> bool {
+    if UefiEnvironment::detect("/").efivars_writable != Some(true) {
+        return false;
+    }
+
+    let variable = match fs::read_dir(EFIVARS).ok().and_then(|mut entries| entries.next()) {
+        Some(Ok(entry)) => entry.path(),
+        _ => return false,
+    };
+
+    let path = match CString::new(variable.as_os_str().as_bytes()) {
+        Ok(ok) => ok,
+        Err(_) => return false,
+    };
+
+    if unsafe { libc::access(path.as_ptr(), libc::W_OK) } == 0 {
+        return true;
+    }
+
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Reads the boot entry set in `BootNext`, if any
+pub fn next_boot() -> Option<u16> {
+    // efivarfs prefixes the value with four bytes of attributes
+    let data = fs::read(BOOT_NEXT_VAR).ok()?;
+    if data.len() != 6 {
+        return None;
+    }
+    Some(u16::from(data[4]) | u16::from(data[5]) << 8)
+}
+
+// efibootmgr only reports failure through its exit status, so a missing
+// privilege is checked for up front to give a clear error
+fn check_privileges(operation: &str) -> Result<(), String> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(format!("insufficient privileges to {}, run as root", operation));
+    }
+
+    Ok(())
+}
+
+pub fn set_next_boot(efi_dir: &str, reporter: &dyn StatusReporter) -> Result<(), String> {
+    check_privileges("set the next boot entry")?;
+
     let mounts = match mount::Mount::all() {
         Ok(ok) => ok,
         Err(err) => {
@@ -66,20 +168,20 @@ pub fn set_next_boot(efi_dir: &str) -> Result<(), String> {
 
     let disk_dev = Path::new("/dev").join(disk_name);
 
-    println!("{} {}", disk_dev.display(), efi_part);
+    reporter.info(&format!("{} {}", disk_dev.display(), efi_part));
 
     {
         let mut command = process::Command::new("efibootmgr");
         command
             .arg("--quiet")
             .arg("--create-only")
-            .arg("--bootnum").arg("1776")
+            .arg("--bootnum").arg(format!("{:04X}", BOOT_NUM))
             .arg("--disk").arg(disk_dev)
             .arg("--part").arg(efi_part)
             .arg("--loader").arg("\\system76-firmware-update\\boot.efi")
             .arg("--label").arg("system76-firmware-update");
 
-        eprintln!("{:?}", command);
+        reporter.info(&format!("{:?}", command));
 
         match command.status() {
             Ok(status) => if ! status.success() {
@@ -95,9 +197,9 @@ pub fn set_next_boot(efi_dir: &str) -> Result<(), String> {
         let mut command = process::Command::new("efibootmgr");
         command
             .arg("--quiet")
-            .arg("--bootnext").arg("1776");
+            .arg("--bootnext").arg(format!("{:04X}", BOOT_NUM));
 
-        eprintln!("{:?}", command);
+        reporter.info(&format!("{:?}", command));
 
         match command.status() {
             Ok(status) => if ! status.success() {
@@ -112,14 +214,16 @@ pub fn set_next_boot(efi_dir: &str) -> Result<(), String> {
     Ok(())
 }
 
-pub fn unset_next_boot() -> Result<(), String> {
+pub fn unset_next_boot(reporter: &dyn StatusReporter) -> Result<(), String> {
+    check_privileges("unset the next boot entry")?;
+
     {
         let mut command = process::Command::new("efibootmgr");
         command
             .arg("--quiet")
             .arg("--delete-bootnext");
 
-        eprintln!("{:?}", command);
+        reporter.info(&format!("{:?}", command));
 
         match command.status() {
             Ok(_status) => (),
@@ -134,9 +238,9 @@ pub fn unset_next_boot() -> Result<(), String> {
         command
             .arg("--quiet")
             .arg("--delete-bootnum")
-            .arg("--bootnum").arg("1776");
+            .arg("--bootnum").arg(format!("{:04X}", BOOT_NUM));
 
-        eprintln!("{:?}", command);
+        reporter.info(&format!("{:?}", command));
 
         match command.status() {
             Ok(_status) => (),
@@ -0,0 +1,97 @@
+use changelog::Changelog;
+use {cached_file, firmware_filename, util, FirmwareId, SystemInfo};
+
+/// A firmware component that a bundle can flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Component {
+    Bios,
+    Ec,
+    Me,
+}
+
+impl Component {
+    // The payloads the updater looks for in the firmware directory
+    fn payloads(&self) -> &'static [&'static str] {
+        match self {
+            Component::Bios => &["firmware.rom"],
+            Component::Ec => &["ec.rom", "ec2.rom"],
+            Component::Me => &["me.rom"],
+        }
+    }
+}
+
+/// A component with a flashing payload in a firmware bundle.
+#[derive(Clone, Debug)]
+pub struct ComponentChange {
+    pub component: Component,
+    /// The version the bundle installs, if its changelog states one.
+    pub version: Option<String>,
+    /// The version currently installed, if it could be read.
+    pub installed: Option<String>,
+    /// Whether flashing would change the installed version. When either
+    /// version is unknown, the component is assumed to change.
+    pub changes: bool,
+}
+
+/// What a firmware bundle will do when flashed.
+#[derive(Clone, Debug)]
+pub struct BundleComponents {
+    /// The components with payloads in the bundle. Components without a
+    /// payload are not listed.
+    pub components: Vec<ComponentChange>,
+    /// The battery must be disconnected, or the machine reset, after flashing.
+    pub requires_battery_reset: bool,
+}
+
+/// Reports what the cached firmware bundle for `firmware_id` will flash,
+/// without flashing anything.
+pub fn bundle_components(digest: &str, firmware_id: &FirmwareId) -> Result<BundleComponents, String> {
+    let file = firmware_filename(firmware_id);
+
+    let files = util::list_files(cached_file(digest, &file)?).map_err(|err| {
+        format!("failed to list files of {}: {}", file, err)
+    })?;
+
+    let changelog = Changelog::parse(
+        &util::extract_file(cached_file(digest, &file)?, "./changelog.json").map_err(|err| {
+            format!("failed to extract changelog.json from {}: {}", file, err)
+        })?
+    )?;
+    let latest = changelog.latest();
+    let installed = SystemInfo::read().versions();
+
+    let mut changes = Vec::new();
+    for &component in &[Component::Bios, Component::Ec, Component::Me] {
+        let present = component.payloads().iter().any(|payload| {
+            files.iter().any(|file| file.as_os_str() == *payload)
+        });
+        if ! present {
+            continue;
+        }
+
+        // The changelog does not describe EC versions
+        let (version, installed) = match component {
+            Component::Bios => (latest.map(|x| x.bios.clone()), installed.bios.clone()),
+            Component::Ec => (None, installed.ec.clone()),
+            Component::Me => (latest.and_then(|x| x.me.clone()), installed.me.clone()),
+        };
+
+        let changes_version = match (&version, &installed) {
+            (Some(version), Some(installed)) => version != installed,
+            _ => true,
+        };
+
+        changes.push(ComponentChange {
+            component,
+            version,
+            installed,
+            changes: changes_version,
+        });
+    }
+
+    Ok(BundleComponents {
+        components: changes,
+        requires_battery_reset: latest.map_or(false, |x| x.requires_battery_reset),
+    })
+}
@@ -0,0 +1,99 @@
+use serde::Deserialize;
+use std::cmp::Ordering;
+
+/// The `changelog.json` shipped in each firmware bundle.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Changelog {
+    pub versions: Vec<Version>,
+}
+
+impl Changelog {
+    /// Parses a changelog, sorting its versions newest first so that the order
+    /// does not depend on how the file was written.
+    pub fn parse(json: &str) -> Result<Changelog, String> {
+        let mut changelog: Changelog = serde_json::from_str(json).map_err(|err| {
+            format!("failed to parse changelog: {}", err)
+        })?;
+        changelog.versions.sort_by(|a, b| version_cmp(&b.bios, &a.bios));
+        Ok(changelog)
+    }
+
+    /// The newest version described by the changelog.
+    pub fn latest(&self) -> Option<&Version> {
+        self.versions.first()
+    }
+}
+
+// Splits a version into runs of digits and runs of other characters
+fn version_parts(version: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut digits = None;
+    for (i, c) in version.char_indices() {
+        let is_digit = c.is_ascii_digit();
+        if digits == Some(! is_digit) {
+            parts.push(&version[start..i]);
+            start = i;
+        }
+        digits = Some(is_digit);
+    }
+    if start < version.len() {
+        parts.push(&version[start..]);
+    }
+    parts
+}
+
+/// Compares versions naturally, so that runs of digits compare by value and
+/// `1.10` sorts after `1.9`.
+pub fn version_cmp(a: &str, b: &str) -> Ordering {
+    let a_parts = version_parts(a);
+    let b_parts = version_parts(b);
+    for (a_part, b_part) in a_parts.iter().zip(b_parts.iter()) {
+        let ordering = match (a_part.parse::<u64>(), b_part.parse::<u64>()) {
+            (Ok(a_num), Ok(b_num)) => a_num.cmp(&b_num),
+            _ => a_part.cmp(b_part),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a_parts.len().cmp(&b_parts.len())
+}
+
+/// Details about a version of firmware.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Version {
+    pub bios: String,
+    pub description: Option<String>,
+    pub me: Option<String>,
+    /// The battery must be disconnected, or the machine reset, after flashing.
+    #[serde(default)]
+    pub requires_battery_reset: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_cmp_natural() {
+        assert_eq!(version_cmp("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(version_cmp("1.07.05RSA1", "1.07.04RSA1"), Ordering::Greater);
+        assert_eq!(version_cmp("1.07", "1.07"), Ordering::Equal);
+        assert_eq!(version_cmp("1.07", "1.07.1"), Ordering::Less);
+        assert_eq!(version_cmp("2020-03-14", "2021-01-01"), Ordering::Less);
+    }
+
+    #[test]
+    fn parse_sorts_newest_first() {
+        let json = r#"{ "versions": [
+            { "bios": "1.9", "description": null, "me": null },
+            { "bios": "1.10", "description": null, "me": null },
+            { "bios": "1.8", "description": null, "me": null }
+        ] }"#;
+        let changelog = Changelog::parse(json).unwrap();
+        let versions = changelog.versions.iter().map(|x| x.bios.as_str()).collect::<Vec<_>>();
+        assert_eq!(versions, vec!["1.10", "1.9", "1.8"]);
+        assert_eq!(changelog.latest().map(|x| x.bios.as_str()), Some("1.10"));
+    }
+}
@@ -1,55 +1,509 @@
 use buildchain::{Downloader, Sha384};
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use err_str;
+use {err_str, redact};
+
+/// The objects accepted and rejected by `Cache::import_dir`.
+#[derive(Clone, Debug, Default)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    /// Files whose contents did not match the digest they are named after.
+    pub rejected: Vec<String>,
+}
+
+/// The objects checked by `Cache::verify`.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    pub verified: Vec<String>,
+    /// Objects whose contents did not match their digest.
+    pub corrupt: Vec<String>,
+}
+
+/// Why `Cache::fetch` could not return an object.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ObjectError {
+    /// The downloader failed to fetch the object. It reports every failure as
+    /// a string, so an object missing from the server cannot be told apart
+    /// from the server being unreachable.
+    #[error(display = "{}", _0)]
+    Download(String),
+    /// The cache could not be read or written, such as when it is full.
+    #[error(display = "{}", _0)]
+    Io(String),
+    /// The object is not cached, and there is no downloader to fetch it.
+    #[error(display = "{} is not in the cache, run download first", _0)]
+    NotCached(String),
+}
+
+// Distinguishes the temporary files of writers within one process
+static WRITE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static BYTES_SAVED: AtomicU64 = AtomicU64::new(0);
+static BYTES_FETCHED: AtomicU64 = AtomicU64::new(0);
+
+/// How well the object caches of this process served it, across every
+/// operation since it started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Objects read from a cache or its fallback.
+    pub hits: u64,
+    /// Objects fetched from the server.
+    pub misses: u64,
+    /// Bytes of the objects read from a cache, which were not fetched.
+    pub bytes_saved: u64,
+    /// Bytes of the objects fetched from the server.
+    pub bytes_fetched: u64,
+}
+
+/// Reads the counters of every `Cache` in this process.
+pub fn cache_stats() -> CacheStats {
+    CacheStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        bytes_saved: BYTES_SAVED.load(Ordering::Relaxed),
+        bytes_fetched: BYTES_FETCHED.load(Ordering::Relaxed),
+    }
+}
+
+fn record_hit(len: u64) {
+    HITS.fetch_add(1, Ordering::Relaxed);
+    BYTES_SAVED.fetch_add(len, Ordering::Relaxed);
+}
+
+fn record_miss(len: u64) {
+    MISSES.fetch_add(1, Ordering::Relaxed);
+    BYTES_FETCHED.fetch_add(len, Ordering::Relaxed);
+}
+
+// Checks that data hashes to the digest it is stored under
+fn verify(data: &[u8], digest: &str) -> Result<bool, String> {
+    let sha = Sha384::new(data).map_err(err_str)?;
+    Ok(sha.to_base32() == digest)
+}
+
+// Opens a file if it holds the object of a digest
+fn open_verified(path: &Path, digest: &str) -> Result<Option<File>, String> {
+    if ! path.is_file() {
+        return Ok(None);
+    }
+
+    let mut file = File::open(path).map_err(err_str)?;
+    let sha = Sha384::new(&mut file).map_err(err_str)?;
+    if sha.to_base32() != digest {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(0)).map_err(err_str)?;
+    Ok(Some(file))
+}
 
 pub struct Cache {
     path: PathBuf,
+    fallback: Option<PathBuf>,
     downloader: Option<Downloader>,
+    force_network: bool,
 }
 
 impl Cache {
     pub fn new<P: AsRef<Path>>(path: P, downloader: Option<Downloader>) -> Result<Cache, String> {
         if ! path.as_ref().is_dir() {
-            fs::create_dir(path.as_ref()).map_err(err_str)?;
+            fs::create_dir_all(path.as_ref()).map_err(err_str)?;
         }
 
         Ok(Cache {
             path: path.as_ref().to_owned(),
-            downloader
+            fallback: None,
+            downloader,
+            force_network: false,
         })
     }
 
+    /// When set, `object` ignores cached copies and fetches every object from
+    /// the downloader again, replacing what is cached.
+    pub fn set_force_network(&mut self, force_network: bool) {
+        self.force_network = force_network;
+    }
+
+    /// Reads objects missing from this cache from another cache, such as the
+    /// system cache for a user who cannot write to it. The fallback is never
+    /// written to: objects that are downloaded are written to this cache.
+    pub fn set_fallback<P: AsRef<Path>>(&mut self, fallback: P) {
+        self.fallback = Some(fallback.as_ref().to_owned());
+    }
+
+    /// Checks whether an object is present in the cache or its fallback. This
+    /// does not verify the object, which `object` still does when reading it.
+    pub fn contains(&self, digest: &str) -> bool {
+        self.path.join(digest).is_file()
+            || self.fallback.as_ref().map_or(false, |fallback| fallback.join(digest).is_file())
+    }
+
     pub fn object(&self, digest: &str) -> Result<Vec<u8>, String> {
-        //TODO: Atomic, with permissions
+        self.fetch(digest).map_err(|err| err.to_string())
+    }
+
+    /// Like `object`, telling apart why the object could not be returned.
+    pub fn fetch(&self, digest: &str) -> Result<Vec<u8>, ObjectError> {
+        //TODO: Permissions
 
         let path = self.path.join(digest);
-        if path.is_file() {
+        if ! self.force_network && path.is_file() {
             let mut data = Vec::new();
             {
-                let mut file = File::open(&path).map_err(err_str)?;
-                file.read_to_end(&mut data).map_err(err_str)?;
+                let mut file = File::open(&path).map_err(|err| ObjectError::Io(err_str(err)))?;
+                file.read_to_end(&mut data).map_err(|err| ObjectError::Io(err_str(err)))?;
             }
 
-            let sha = Sha384::new(data.as_slice()).map_err(err_str)?;
-            if &sha.to_base32() == digest {
+            if verify(&data, digest).map_err(ObjectError::Io)? {
+                verbose!("using cached {}", digest);
+                record_hit(data.len() as u64);
                 return Ok(data);
             } else {
-                fs::remove_file(&path).map_err(err_str)?;
+                fs::remove_file(&path).map_err(|err| ObjectError::Io(err_str(err)))?;
             }
         }
 
-        if let Some(ref downloader) = self.downloader {
-            let data = downloader.object(digest)?;
-            {
-                let mut file = File::create(&path).map_err(err_str)?;
-                file.write_all(&data).map_err(err_str)?;
+        if ! self.force_network {
+            if let Some(ref fallback) = self.fallback {
+                let fallback_path = fallback.join(digest);
+                if fallback_path.is_file() {
+                    let data = fs::read(&fallback_path).map_err(|err| ObjectError::Io(err_str(err)))?;
+                    if verify(&data, digest).map_err(ObjectError::Io)? {
+                        verbose!("using cached {} from {}", digest, fallback.display());
+                        record_hit(data.len() as u64);
+                        return Ok(data);
+                    }
+                }
             }
+        }
+
+        if let Some(ref downloader) = self.downloader {
+            let data = downloader.object(digest).map_err(|err| ObjectError::Download(redact(&err)))?;
+            record_miss(data.len() as u64);
+            self.write(digest, &data).map_err(ObjectError::Io)?;
             Ok(data)
         } else {
-            Err(format!("could not find digest in cache: {}", digest))
+            Err(ObjectError::NotCached(digest.to_string()))
+        }
+    }
+
+    /// Opens a verified object for streaming, so that large objects already in
+    /// the cache are never read into memory as a whole.
+    pub fn open(&self, digest: &str) -> Result<File, String> {
+        let path = self.path.join(digest);
+        if ! self.force_network {
+            if let Some(file) = open_verified(&path, digest)? {
+                verbose!("using cached {}", digest);
+                record_hit(file.metadata().map_or(0, |metadata| metadata.len()));
+                return Ok(file);
+            }
+
+            if let Some(ref fallback) = self.fallback {
+                if let Some(file) = open_verified(&fallback.join(digest), digest)? {
+                    verbose!("using cached {} from {}", digest, fallback.display());
+                    record_hit(file.metadata().map_or(0, |metadata| metadata.len()));
+                    return Ok(file);
+                }
+            }
+        }
+
+        // Missing or corrupt, so let object replace it
+        self.object(digest)?;
+        File::open(&path).map_err(err_str)
+    }
+
+    // Writes through a temporary file unique to this writer and renames it
+    // into place, so that processes writing the same object at once never
+    // corrupt it and readers never see a partial object
+    fn write(&self, digest: &str, data: &[u8]) -> Result<(), String> {
+        let tmp_path = self.path.join(format!(
+            "{}.{}.{}.tmp",
+            digest,
+            process::id(),
+            WRITE_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        let result = File::create(&tmp_path).and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        }).and_then(|()| {
+            fs::rename(&tmp_path, self.path.join(digest))
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
         }
+
+        result.map_err(err_str)
+    }
+
+    /// Re-hashes every cached object against the digest it is named after,
+    /// optionally deleting those that do not match. Files that are not named
+    /// like a digest, such as the update history, are skipped.
+    pub fn verify(&self, delete: bool) -> Result<VerifyReport, String> {
+        let mut report = VerifyReport::default();
+
+        for entry_res in fs::read_dir(&self.path).map_err(err_str)? {
+            let entry = entry_res.map_err(err_str)?;
+            if ! entry.file_type().map_err(err_str)?.is_file() {
+                continue;
+            }
+
+            // Digests are base32, so never contain a dot
+            let digest = match entry.file_name().into_string() {
+                Ok(ok) => if ok.contains('.') {
+                    continue;
+                } else {
+                    ok
+                },
+                Err(_) => continue,
+            };
+
+            let mut file = File::open(entry.path()).map_err(err_str)?;
+            let sha = Sha384::new(&mut file).map_err(err_str)?;
+            if sha.to_base32() == digest {
+                report.verified.push(digest);
+            } else {
+                status!("{} is corrupt", entry.path().display());
+                if delete {
+                    fs::remove_file(entry.path()).map_err(err_str)?;
+                }
+                report.corrupt.push(digest);
+            }
+        }
+
+        report.verified.sort();
+        report.corrupt.sort();
+
+        Ok(report)
+    }
+
+    /// Seeds the cache from a directory of objects named by their digests, such
+    /// as one written by `export_dir` on another machine. Each file is verified
+    /// against its name, and any that do not match are rejected.
+    pub fn import_dir<P: AsRef<Path>>(&self, src: P) -> Result<ImportReport, String> {
+        let mut report = ImportReport::default();
+
+        for entry_res in fs::read_dir(src.as_ref()).map_err(err_str)? {
+            let entry = entry_res.map_err(err_str)?;
+            if ! entry.file_type().map_err(err_str)?.is_file() {
+                continue;
+            }
+
+            let digest = match entry.file_name().into_string() {
+                Ok(ok) => ok,
+                Err(name) => {
+                    report.rejected.push(name.to_string_lossy().into_owned());
+                    continue;
+                }
+            };
+
+            let data = fs::read(entry.path()).map_err(err_str)?;
+            if verify(&data, &digest)? {
+                self.write(&digest, &data)?;
+                report.imported.push(digest);
+            } else {
+                status!("rejecting {}: contents do not match digest", entry.path().display());
+                report.rejected.push(digest);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Copies the given objects into a directory, named by their digests, for
+    /// seeding the cache of an offline machine with `import_dir`.
+    pub fn export_dir<P: AsRef<Path>>(&self, digests: &[&str], dst: P) -> Result<(), String> {
+        if ! dst.as_ref().is_dir() {
+            fs::create_dir_all(dst.as_ref()).map_err(err_str)?;
+        }
+
+        for digest in digests {
+            let data = self.object(digest)?;
+            fs::write(dst.as_ref().join(digest), &data).map_err(err_str)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, thread};
+    use tempdir::TempDir;
+
+    fn digest(data: &[u8]) -> String {
+        Sha384::new(data).unwrap().to_base32()
+    }
+
+    #[test]
+    fn offline_hit_and_miss() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "cache").map_err(err_str)?;
+        let cache = Cache::new(tmp.path(), None)?;
+        let hit = digest(b"cached");
+        fs::write(tmp.path().join(&hit), b"cached").map_err(err_str)?;
+
+        assert!(cache.contains(&hit));
+        assert_eq!(cache.object(&hit)?, b"cached");
+
+        let miss = digest(b"missing");
+        assert!(! cache.contains(&miss));
+        let err = cache.fetch(&miss).unwrap_err();
+        assert!(matches!(err, ObjectError::NotCached(_)), "{:?}", err);
+        assert!(err.to_string().contains("run download first"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn open_rejects_digest_mismatch() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "cache").map_err(err_str)?;
+        let cache = Cache::new(tmp.path(), None)?;
+        let corrupt = digest(b"original");
+        fs::write(tmp.path().join(&corrupt), b"tampered").map_err(err_str)?;
+
+        // A corrupt object is a miss, and offline it cannot be replaced
+        let err = cache.open(&corrupt).unwrap_err();
+        assert!(err.contains("is not in the cache"), "{}", err);
+        assert!(! tmp.path().join(&corrupt).exists());
+
+        let good = digest(b"original");
+        fs::write(tmp.path().join(&good), b"original").map_err(err_str)?;
+        let mut data = Vec::new();
+        cache.open(&good)?.read_to_end(&mut data).map_err(err_str)?;
+        assert_eq!(data, b"original");
+        Ok(())
+    }
+
+    #[test]
+    fn import_export_round_trip() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "cache").map_err(err_str)?;
+        let online = Cache::new(tmp.path().join("online"), None)?;
+        let good = digest(b"good");
+        online.write(&good, b"good")?;
+
+        let media = tmp.path().join("media");
+        online.export_dir(&[&good], &media)?;
+        let tampered = digest(b"original");
+        fs::write(media.join(&tampered), b"tampered").map_err(err_str)?;
+
+        let offline = Cache::new(tmp.path().join("offline"), None)?;
+        let report = offline.import_dir(&media)?;
+        assert_eq!(report.imported, vec![good.clone()]);
+        assert_eq!(report.rejected, vec![tampered.clone()]);
+        assert_eq!(offline.object(&good)?, b"good");
+        assert!(! offline.contains(&tampered));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_detects_corrupt_objects() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "cache").map_err(err_str)?;
+        let cache = Cache::new(tmp.path(), None)?;
+        let good = digest(b"good");
+        let corrupt = digest(b"original");
+        fs::write(tmp.path().join(&good), b"good").map_err(err_str)?;
+        fs::write(tmp.path().join(&corrupt), b"tampered").map_err(err_str)?;
+        // Files named unlike a digest are not objects
+        fs::write(tmp.path().join("history.jsonl"), b"{}").map_err(err_str)?;
+        fs::write(tmp.path().join(format!("{}.1.0.tmp", good)), b"partial").map_err(err_str)?;
+        fs::create_dir(tmp.path().join("directory")).map_err(err_str)?;
+
+        let report = cache.verify(false)?;
+        assert_eq!(report.verified, vec![good.clone()]);
+        assert_eq!(report.corrupt, vec![corrupt.clone()]);
+        assert!(tmp.path().join(&corrupt).exists());
+        assert!(tmp.path().join("history.jsonl").exists());
+
+        let report = cache.verify(true)?;
+        assert_eq!(report.corrupt, vec![corrupt.clone()]);
+        assert!(! tmp.path().join(&corrupt).exists());
+        assert!(tmp.path().join("history.jsonl").exists());
+        assert!(cache.verify(false)?.corrupt.is_empty());
+        Ok(())
+    }
+
+    // Lists the temporary files left in a cache
+    fn tmp_files(dir: &Path) -> Vec<String> {
+        fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".tmp"))
+            .collect()
+    }
+
+    #[test]
+    fn concurrent_writes_are_atomic() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "cache").map_err(err_str)?;
+        let data = vec![0x5A; 1 << 20];
+        let object = digest(&data);
+
+        let writers: Vec<_> = (0..8).map(|_| {
+            let path = tmp.path().to_owned();
+            let data = data.clone();
+            let object = object.clone();
+            thread::spawn(move || {
+                let cache = Cache::new(path, None).unwrap();
+                for _ in 0..4 {
+                    cache.write(&object, &data).unwrap();
+                    assert_eq!(cache.object(&object).unwrap(), data);
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().map_err(|_| "writer panicked".to_string())?;
+        }
+
+        assert_eq!(fs::read(tmp.path().join(&object)).map_err(err_str)?, data);
+        assert_eq!(tmp_files(tmp.path()), Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_write_leaves_no_tmp_file() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "cache").map_err(err_str)?;
+        let cache = Cache::new(tmp.path(), None)?;
+        let object = digest(b"object");
+        // A directory in the way makes the rename into place fail
+        fs::create_dir(tmp.path().join(&object)).map_err(err_str)?;
+
+        assert!(cache.write(&object, b"object").is_err());
+        assert_eq!(tmp_files(tmp.path()), Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_through_to_fallback() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "cache").map_err(err_str)?;
+        let system = Cache::new(tmp.path().join("system"), None)?;
+        let shared = digest(b"shared");
+        system.write(&shared, b"shared")?;
+        let corrupt = digest(b"original");
+        fs::write(tmp.path().join("system").join(&corrupt), b"tampered").map_err(err_str)?;
+
+        let mut user = Cache::new(tmp.path().join("user"), None)?;
+        user.set_fallback(tmp.path().join("system"));
+        assert!(user.contains(&shared));
+        assert_eq!(user.object(&shared)?, b"shared");
+        let mut data = Vec::new();
+        user.open(&shared)?.read_to_end(&mut data).map_err(err_str)?;
+        assert_eq!(data, b"shared");
+
+        // Reading never copies into the user cache, and writes go only there
+        assert!(! tmp.path().join("user").join(&shared).exists());
+        let own = digest(b"own");
+        user.write(&own, b"own")?;
+        assert!(tmp.path().join("user").join(&own).exists());
+        assert!(! tmp.path().join("system").join(&own).exists());
+
+        // A corrupt fallback object is a miss, and is left for root to repair
+        assert!(matches!(user.fetch(&corrupt), Err(ObjectError::NotCached(_))));
+        assert!(tmp.path().join("system").join(&corrupt).exists());
+        Ok(())
     }
 }
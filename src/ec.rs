@@ -1,4 +1,8 @@
-use ecflash::{Ec, EcFlash};
+use ecflash::{Ec, EcFlash, Flasher};
+use std::fs;
+use std::path::Path;
+
+use {io_err_str, power};
 
 pub fn ec(primary: bool) -> Result<(String, String), String> {
     let mut ec = EcFlash::new(primary)?;
@@ -11,3 +15,155 @@ pub fn ec_or_none(primary: bool) -> (String, String) {
         Err(_err) => ("none".to_string(), "".to_string())
     }
 }
+
+/// How many times to ask the EC to leave flash mode before giving up, since an
+/// EC left in flash mode does not run its firmware.
+const STOP_ATTEMPTS: usize = 3;
+
+// The flash mode steps of a read-out, so that backups can be tested without an EC
+trait ReadOut {
+    unsafe fn start(&mut self) -> Result<u8, ()>;
+    unsafe fn read(&mut self) -> Result<Vec<u8>, ()>;
+    unsafe fn stop(&mut self) -> Result<u8, ()>;
+}
+
+impl ReadOut for Flasher {
+    unsafe fn start(&mut self) -> Result<u8, ()> {
+        Flasher::start(self)
+    }
+
+    unsafe fn read(&mut self) -> Result<Vec<u8>, ()> {
+        Flasher::read(self, |_progress| ())
+    }
+
+    unsafe fn stop(&mut self) -> Result<u8, ()> {
+        Flasher::stop(self)
+    }
+}
+
+// Leaves flash mode, retrying because the EC stays stopped until this succeeds
+unsafe fn stop<F: ReadOut>(flasher: &mut F) -> Result<(), String> {
+    for attempt in 1..=STOP_ATTEMPTS {
+        if flasher.stop().is_ok() {
+            return Ok(());
+        }
+        status!("failed to stop EC flash mode (attempt {} of {})", attempt, STOP_ATTEMPTS);
+    }
+
+    Err(format!(
+        "failed to stop EC flash mode after {} attempts: the EC is not running its \
+         firmware, so fans and keyboard may not respond until the machine is power cycled",
+        STOP_ATTEMPTS
+    ))
+}
+
+unsafe fn read_out<F: ReadOut>(flasher: &mut F) -> Result<Vec<u8>, String> {
+    if flasher.start() != Ok(0x33) {
+        stop(flasher)?;
+        return Err("failed to start EC flash mode".to_string());
+    }
+
+    let data = flasher.read();
+    stop(flasher)?;
+    data.map_err(|()| "failed to read EC flash".to_string())
+}
+
+fn backup_from<F: ReadOut, P: AsRef<Path>>(flasher: &mut F, out: P) -> Result<(), String> {
+    let data = unsafe { read_out(flasher)? };
+
+    fs::write(out.as_ref(), &data).map_err(|err| {
+        io_err_str(&format!("write {}", out.as_ref().display()), err)
+    })
+}
+
+/// Reads out the EC firmware and writes it to `out`, for restoring a known-good
+/// image later. The EC stops running its firmware while it is read, so fan and
+/// keyboard control pause until the read finishes, and the read is refused
+/// while running on battery.
+pub fn backup<P: AsRef<Path>>(primary: bool, out: P) -> Result<(), String> {
+    if power::ac_online() == Some(false) {
+        return Err("AC power is not connected".to_string());
+    }
+
+    let ec = EcFlash::new(primary)?;
+    backup_from(&mut Flasher::new(ec), out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tempdir::TempDir;
+
+    struct MockFlasher {
+        start: Result<u8, ()>,
+        read: Result<Vec<u8>, ()>,
+        // Stop attempts that fail before one succeeds
+        stop_failures: usize,
+        stops: usize,
+    }
+
+    impl MockFlasher {
+        fn new(read: Result<Vec<u8>, ()>, stop_failures: usize) -> MockFlasher {
+            MockFlasher { start: Ok(0x33), read, stop_failures, stops: 0 }
+        }
+    }
+
+    impl ReadOut for MockFlasher {
+        unsafe fn start(&mut self) -> Result<u8, ()> {
+            self.start
+        }
+
+        unsafe fn read(&mut self) -> Result<Vec<u8>, ()> {
+            self.read.clone()
+        }
+
+        unsafe fn stop(&mut self) -> Result<u8, ()> {
+            self.stops += 1;
+            if self.stops > self.stop_failures { Ok(0) } else { Err(()) }
+        }
+    }
+
+    #[test]
+    fn backup_writes_flash_contents() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "ec-backup").map_err(|err| err.to_string())?;
+        let out = tmp.path().join("ec.rom");
+
+        let mut flasher = MockFlasher::new(Ok(vec![0xAA, 0x55, 0x00]), 0);
+        backup_from(&mut flasher, &out)?;
+        assert_eq!(fs::read(&out).map_err(|err| err.to_string())?, [0xAA, 0x55, 0x00]);
+        assert_eq!(flasher.stops, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn backup_retries_stop() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "ec-backup").map_err(|err| err.to_string())?;
+        let out = tmp.path().join("ec.rom");
+
+        let mut flasher = MockFlasher::new(Ok(vec![0xAA]), STOP_ATTEMPTS - 1);
+        backup_from(&mut flasher, &out)?;
+        assert_eq!(flasher.stops, STOP_ATTEMPTS);
+
+        let mut flasher = MockFlasher::new(Ok(vec![0xAA]), STOP_ATTEMPTS);
+        let err = backup_from(&mut flasher, tmp.path().join("stuck.rom")).unwrap_err();
+        assert!(err.contains("failed to stop EC flash mode"), "{}", err);
+        assert_eq!(flasher.stops, STOP_ATTEMPTS);
+        assert!(!tmp.path().join("stuck.rom").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn backup_stops_after_failed_start_and_read() {
+        let mut flasher = MockFlasher::new(Ok(vec![0xAA]), 0);
+        flasher.start = Ok(0);
+        let err = backup_from(&mut flasher, "/nonexistent/ec.rom").unwrap_err();
+        assert_eq!(err, "failed to start EC flash mode");
+        assert_eq!(flasher.stops, 1);
+
+        let mut flasher = MockFlasher::new(Err(()), 0);
+        let err = backup_from(&mut flasher, "/nonexistent/ec.rom").unwrap_err();
+        assert_eq!(err, "failed to read EC flash");
+        assert_eq!(flasher.stops, 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::Path;
+
+/// The EFI System Resource Table, as exposed by the kernel
+const ESRT_ENTRIES: &str = "/sys/firmware/efi/esrt/entries";
+
+/// Firmware that UEFI can update with capsules, from an ESRT entry.
+#[derive(Clone, Debug)]
+pub struct EsrtEntry {
+    /// The GUID identifying the firmware.
+    pub fw_class: String,
+    /// 0 is unknown, 1 system firmware, 2 device firmware, 3 a UEFI driver.
+    pub fw_type: u32,
+    pub fw_version: u32,
+    pub lowest_supported_fw_version: u32,
+    pub capsule_flags: u32,
+    pub last_attempt_version: u32,
+    pub last_attempt_status: u32,
+}
+
+fn read_u32(path: &Path, name: &str) -> Result<u32, String> {
+    let path = path.join(name);
+    let value = fs::read_to_string(&path).map_err(|err| {
+        format!("failed to read {}: {}", path.display(), err)
+    })?;
+
+    let value = value.trim();
+    let result = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse::<u32>(),
+    };
+    result.map_err(|err| format!("failed to parse {}: {}", path.display(), err))
+}
+
+/// Lists the firmware in the ESRT, sorted by GUID. This is informational: the
+/// updater staged by `schedule` does not use capsules.
+pub fn esrt_entries() -> Result<Vec<EsrtEntry>, String> {
+    read_entries(Path::new(ESRT_ENTRIES))
+}
+
+// Reads ESRT entries laid out as the kernel does, one directory per entry
+fn read_entries(entries_dir: &Path) -> Result<Vec<EsrtEntry>, String> {
+    let mut entries = Vec::new();
+
+    let dir = match fs::read_dir(entries_dir) {
+        Ok(ok) => ok,
+        Err(_) => return Ok(entries),
+    };
+
+    for entry_res in dir {
+        let path = entry_res.map_err(|err| format!("failed to read {}: {}", entries_dir.display(), err))?.path();
+
+        let fw_class_path = path.join("fw_class");
+        let fw_class = fs::read_to_string(&fw_class_path).map_err(|err| {
+            format!("failed to read {}: {}", fw_class_path.display(), err)
+        })?.trim().to_string();
+
+        entries.push(EsrtEntry {
+            fw_class,
+            fw_type: read_u32(&path, "fw_type")?,
+            fw_version: read_u32(&path, "fw_version")?,
+            lowest_supported_fw_version: read_u32(&path, "lowest_supported_fw_version")?,
+            capsule_flags: read_u32(&path, "capsule_flags")?,
+            last_attempt_version: read_u32(&path, "last_attempt_version")?,
+            last_attempt_status: read_u32(&path, "last_attempt_status")?,
+        });
+    }
+
+    entries.sort_by(|a, b| a.fw_class.cmp(&b.fw_class));
+
+    Ok(entries)
+}
+
+/// Checks whether the firmware lists anything that capsule updates could
+/// update.
+pub fn capsule_supported() -> bool {
+    esrt_entries().map_or(false, |entries| ! entries.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tempdir::TempDir;
+
+    fn write_entry(dir: &Path, name: &str, fw_class: &str, fw_version: &str) {
+        let entry = dir.join(name);
+        fs::create_dir_all(&entry).unwrap();
+        fs::write(entry.join("fw_class"), format!("{}\n", fw_class)).unwrap();
+        fs::write(entry.join("fw_type"), "1\n").unwrap();
+        fs::write(entry.join("fw_version"), format!("{}\n", fw_version)).unwrap();
+        fs::write(entry.join("lowest_supported_fw_version"), "0\n").unwrap();
+        fs::write(entry.join("capsule_flags"), "0x8010\n").unwrap();
+        fs::write(entry.join("last_attempt_version"), "0\n").unwrap();
+        fs::write(entry.join("last_attempt_status"), "0\n").unwrap();
+    }
+
+    #[test]
+    fn read_entries_from_sysfs_layout() {
+        let tmp = TempDir::new_in(env::temp_dir(), "esrt").unwrap();
+        let dir = tmp.path().join("entries");
+        write_entry(&dir, "entry0", "ddc0ee61-e7f0-4e7d-acc5-c070a398838e", "65586");
+        write_entry(&dir, "entry1", "5b92717b-2cad-4a96-a13b-9d65781df8bd", "0x10");
+
+        let entries = read_entries(&dir).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].fw_class, "5b92717b-2cad-4a96-a13b-9d65781df8bd");
+        assert_eq!(entries[0].fw_version, 0x10);
+        assert_eq!(entries[0].capsule_flags, 0x8010);
+        assert_eq!(entries[1].fw_type, 1);
+        assert_eq!(entries[1].fw_version, 65586);
+
+        // Firmware without an ESRT lists nothing
+        assert!(read_entries(&tmp.path().join("missing")).unwrap().is_empty());
+
+        fs::write(dir.join("entry1").join("fw_version"), "version\n").unwrap();
+        let err = read_entries(&dir).unwrap_err();
+        assert!(err.contains("fw_version"), "{}", err);
+    }
+}
@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use changelog::Changelog;
+use {config, StatusReporter, StderrReporter};
+
+/// The history file, stored as JSON lines in the cache directory
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// The number of entries kept, older entries are dropped when appending
+const HISTORY_LIMIT: usize = 100;
+
+/// Versions of each firmware component, where they are known.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ComponentVersions {
+    pub bios: Option<String>,
+    pub ec: Option<String>,
+    pub me: Option<String>,
+}
+
+impl ComponentVersions {
+    /// The versions of the newest changelog entry. The changelog does not
+    /// describe EC versions.
+    pub fn from_changelog(changelog: &Changelog) -> ComponentVersions {
+        match changelog.latest() {
+            Some(latest) => ComponentVersions {
+                bios: Some(latest.bios.clone()),
+                ec: None,
+                me: latest.me.clone(),
+            },
+            None => ComponentVersions::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HistoryOutcome {
+    Scheduled,
+    AlreadyUpToDate,
+    Failed(String),
+}
+
+/// A record of one attempt to update the firmware.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HistoryEntry {
+    /// Seconds since the unix epoch.
+    pub time: u64,
+    pub firmware_id: String,
+    pub digest: String,
+    /// The versions installed when the entry was recorded.
+    pub from: ComponentVersions,
+    /// The versions the bundle installs.
+    pub to: ComponentVersions,
+    pub outcome: HistoryOutcome,
+    /// The `bundle_revision_hash` of the manifest, if it could be loaded.
+    #[serde(default)]
+    pub revision: Option<String>,
+    /// The TPM PCR 0 measurement of the firmware when the entry was recorded,
+    /// if there is a TPM. It should differ in the first entry after a flash.
+    #[serde(default)]
+    pub pcr0: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn new(firmware_id: String, digest: String, from: ComponentVersions, to: ComponentVersions, outcome: HistoryOutcome) -> HistoryEntry {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+
+        HistoryEntry { time, firmware_id, digest, from, to, outcome, revision: None, pcr0: None }
+    }
+}
+
+/// Reads the history in `dir`, oldest first. Each line is parsed on its own,
+/// and lines that cannot be parsed are skipped with a warning to `reporter`
+/// rather than hiding every other entry.
+pub fn read(dir: &Path, reporter: &dyn StatusReporter) -> Result<Vec<HistoryEntry>, String> {
+    let path = dir.join(HISTORY_FILE);
+    let data = match fs::read_to_string(&path) {
+        Ok(ok) => ok,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(format!("failed to read {}: {}", path.display(), err));
+        }
+    };
+
+    let mut entries = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<HistoryEntry>(line) {
+            Ok(ok) => entries.push(ok),
+            Err(err) => reporter.warning(&format!(
+                "skipping history entry: failed to parse {} line {}: {}",
+                path.display(), i + 1, err
+            )),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Appends an entry to the history in `dir`, dropping the oldest entries past
+/// the limit. The file is rewritten through a temporary file and renamed into
+/// place, so a reader never sees a partial history. Lines that cannot be
+/// parsed are dropped, as `read` skips them.
+pub fn append(dir: &Path, entry: HistoryEntry, reporter: &dyn StatusReporter) -> Result<(), String> {
+    let path = dir.join(HISTORY_FILE);
+    let tmp_path = dir.join(format!("{}.tmp", HISTORY_FILE));
+
+    let mut entries = read(dir, reporter)?;
+    entries.push(entry);
+    if entries.len() > HISTORY_LIMIT {
+        let excess = entries.len() - HISTORY_LIMIT;
+        entries.drain(..excess);
+    }
+
+    let mut data = Vec::new();
+    for entry in entries.iter() {
+        serde_json::to_writer(&mut data, entry).map_err(|err| {
+            format!("failed to serialize history: {}", err)
+        })?;
+        data.push(b'\n');
+    }
+
+    {
+        let mut file = fs::File::create(&tmp_path).map_err(|err| {
+            format!("failed to create {}: {}", tmp_path.display(), err)
+        })?;
+        file.write_all(&data).and_then(|()| file.sync_all()).map_err(|err| {
+            format!("failed to write {}: {}", tmp_path.display(), err)
+        })?;
+    }
+
+    fs::rename(&tmp_path, &path).map_err(|err| {
+        format!("failed to move {} to {}: {}", tmp_path.display(), path.display(), err)
+    })
+}
+
+/// Reads the recorded update history, oldest first.
+pub fn update_history() -> Result<Vec<HistoryEntry>, String> {
+    update_history_with_reporter(&StderrReporter)
+}
+
+/// Like `update_history()`, sending warnings about skipped entries to
+/// `reporter` instead of stderr.
+pub fn update_history_with_reporter(reporter: &dyn StatusReporter) -> Result<Vec<HistoryEntry>, String> {
+    read(Path::new(config::CACHE), reporter)
+}
+
+/// Finds the most recent scheduled update of a firmware id in the history in
+/// `dir`.
+pub fn last_scheduled(dir: &Path, firmware_id: &str, reporter: &dyn StatusReporter) -> Result<Option<HistoryEntry>, String> {
+    Ok(read(dir, reporter)?.into_iter().rev().find(|entry| {
+        entry.firmware_id == firmware_id && entry.outcome == HistoryOutcome::Scheduled
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use tempdir::TempDir;
+
+    #[derive(Default)]
+    struct Warnings(RefCell<Vec<String>>);
+
+    impl StatusReporter for Warnings {
+        fn warning(&self, message: &str) {
+            self.0.borrow_mut().push(message.to_string());
+        }
+    }
+
+    fn entry(firmware_id: &str) -> HistoryEntry {
+        HistoryEntry::new(
+            firmware_id.to_string(),
+            "digest".to_string(),
+            ComponentVersions::default(),
+            ComponentVersions::default(),
+            HistoryOutcome::Scheduled,
+        )
+    }
+
+    #[test]
+    fn append_read_and_truncate() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "history").map_err(|err| err.to_string())?;
+        let warnings = Warnings::default();
+
+        assert!(read(tmp.path(), &warnings)?.is_empty());
+
+        for i in 0..HISTORY_LIMIT + 1 {
+            append(tmp.path(), entry(&i.to_string()), &warnings)?;
+        }
+
+        let entries = read(tmp.path(), &warnings)?;
+        assert_eq!(entries.len(), HISTORY_LIMIT);
+        assert_eq!(entries[0].firmware_id, "1");
+        assert_eq!(entries[HISTORY_LIMIT - 1].firmware_id, HISTORY_LIMIT.to_string());
+        assert!(!tmp.path().join(format!("{}.tmp", HISTORY_FILE)).exists());
+
+        let last = last_scheduled(tmp.path(), "7", &warnings)?;
+        assert_eq!(last.map(|entry| entry.firmware_id), Some("7".to_string()));
+        assert!(last_scheduled(tmp.path(), "0", &warnings)?.is_none());
+
+        assert!(warnings.0.borrow().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn read_skips_malformed_lines() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "history").map_err(|err| err.to_string())?;
+        let good = serde_json::to_string(&entry("good")).map_err(|err| err.to_string())?;
+        let data = format!("{}\nnot json\n\n{}\n", good, good);
+        fs::write(tmp.path().join(HISTORY_FILE), data).map_err(|err| err.to_string())?;
+
+        let warnings = Warnings::default();
+        let entries = read(tmp.path(), &warnings)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(warnings.0.borrow().len(), 1);
+        assert!(warnings.0.borrow()[0].contains("line 2"), "{:?}", warnings.0.borrow());
+
+        // Appending keeps the parsed entries and drops the malformed line
+        append(tmp.path(), entry("new"), &warnings)?;
+        assert_eq!(read(tmp.path(), &Warnings::default())?.len(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::str::FromStr;
+
+use util;
+
+/// EC projects that `ec_project_from_hash` can translate a firmware id back
+/// to. `none` is the project used when the EC cannot be read, as on desktops.
+/// Projects are added here as they are confirmed.
+const KNOWN_EC_PROJECTS: &[&str] = &["none"];
+
+/// The hash of an EC project as it appears in a firmware id: the lowercase hex
+/// SHA-256 of the project string, exactly as the EC reports it.
+pub fn ec_project_hash(project: &str) -> String {
+    util::sha256(project.as_bytes())
+}
+
+/// Looks up the EC project of the hash in a firmware id, among the known
+/// projects.
+pub fn ec_project_from_hash(hash: &str) -> Option<&'static str> {
+    KNOWN_EC_PROJECTS.iter().find(|project| ec_project_hash(project) == hash).cloned()
+}
+
+// Allows only ASCII letters, digits, dashes and dots, and no leading dot, so
+// that the model cannot name another directory or a hidden file
+fn check_model(model: &str) -> Result<(), String> {
+    if model.is_empty() {
+        return Err("invalid firmware id: missing model".to_string());
+    }
+
+    let safe = model.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.');
+    if ! safe || model.starts_with('.') {
+        return Err(format!("invalid firmware id: model {:?} is not a safe filename", model));
+    }
+
+    Ok(())
+}
+
+/// Identifies the firmware bundle for a machine: the BIOS model followed by the
+/// SHA-256 of the EC project, separated by an underscore.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FirmwareId(String);
+
+impl FirmwareId {
+    /// The model is used verbatim. Suffixes such as `-ess` are never stripped,
+    /// so a special SKU resolves to its own bundle and there is no fallback to
+    /// the firmware of its base model.
+    ///
+    /// The id becomes a filename, so a model that is not a safe filename, as
+    /// may come from a corrupt DMI table, is rejected.
+    pub fn new(model: &str, project: &str) -> Result<FirmwareId, String> {
+        check_model(model)?;
+        let project_hash = ec_project_hash(project);
+        Ok(FirmwareId(format!("{}_{}", model, project_hash)))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Splits the id into the BIOS model and the EC project hash.
+    pub fn split(&self) -> (&str, &str) {
+        // Models may contain dashes and dots, but the hash never contains an
+        // underscore, so the last one is the separator
+        match self.0.rfind('_') {
+            Some(index) => (&self.0[..index], &self.0[index + 1..]),
+            None => (&self.0, ""),
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        self.split().0
+    }
+
+    pub fn ec_hash(&self) -> &str {
+        self.split().1
+    }
+}
+
+impl fmt::Display for FirmwareId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for FirmwareId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FirmwareId, String> {
+        let index = s.rfind('_').ok_or(format!("invalid firmware id {}: missing separator", s))?;
+        let (model, hash) = (&s[..index], &s[index + 1..]);
+
+        check_model(model)?;
+
+        // `ec_project_hash` is lowercase, so an uppercase spelling of the same
+        // id is not accepted as a different id
+        let lower_hex = |b: u8| b.is_ascii_digit() || (b'a'..=b'f').contains(&b);
+        if hash.len() != 64 || ! hash.bytes().all(lower_hex) {
+            return Err(format!("invalid firmware id {}: malformed EC project hash", s));
+        }
+
+        Ok(FirmwareId(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ec_project_hash_of_none() {
+        // The project used when the EC cannot be read
+        assert_eq!(
+            ec_project_hash("none"),
+            "140bedbf9c3f6d56a9846d2ba7088798683f4da0c248231336e6a05679e4fdfe"
+        );
+    }
+
+    #[test]
+    fn ec_project_from_known_hash() {
+        assert_eq!(ec_project_from_hash(HASH), Some("none"));
+        assert_eq!(ec_project_from_hash(&"0".repeat(64)), None);
+        assert_eq!(ec_project_from_hash(""), None);
+    }
+
+    const HASH: &str = "140bedbf9c3f6d56a9846d2ba7088798683f4da0c248231336e6a05679e4fdfe";
+
+    #[test]
+    fn from_str_and_split() {
+        let id = format!("thelio-major-b1.1_{}", HASH).parse::<FirmwareId>().unwrap();
+        assert_eq!(id.split(), ("thelio-major-b1.1", HASH));
+        assert_eq!(id.model(), "thelio-major-b1.1");
+        assert_eq!(id.ec_hash(), HASH);
+        assert_eq!(id.to_string(), format!("thelio-major-b1.1_{}", HASH));
+    }
+
+    #[test]
+    fn from_str_rejects_malformed() {
+        assert!("galp3".parse::<FirmwareId>().is_err());
+        assert!(format!("_{}", HASH).parse::<FirmwareId>().is_err());
+        assert!("galp3_0123".parse::<FirmwareId>().is_err());
+        assert!(format!("galp3_{}", HASH.replace('a', "g")).parse::<FirmwareId>().is_err());
+    }
+
+    #[test]
+    fn from_str_rejects_uppercase_hash() {
+        assert!(format!("galp3_{}", HASH.to_uppercase()).parse::<FirmwareId>().is_err());
+        assert!(format!("galp3_{}", HASH).parse::<FirmwareId>().is_ok());
+    }
+
+    #[test]
+    fn rejects_unsafe_models() {
+        for model in &["../galp3", "galp3/..", "..", ".galp3", "galp3/b", "galp3 b", ""] {
+            assert!(FirmwareId::new(model, "none").is_err(), "{:?}", model);
+            assert!(format!("{}_{}", model, HASH).parse::<FirmwareId>().is_err(), "{:?}", model);
+        }
+        assert!(FirmwareId::new("galp3-c", "none").is_ok());
+    }
+}
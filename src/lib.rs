@@ -1,4 +1,9 @@
+//! Public enums are `#[non_exhaustive]`, so that variants can be added without
+//! breaking embedders. A `match` on one outside this crate needs a wildcard arm.
+
 extern crate buildchain;
+#[macro_use]
+extern crate err_derive;
 extern crate ecflash;
 extern crate libc;
 extern crate lzma;
@@ -10,83 +15,142 @@ extern crate tar;
 extern crate tempdir;
 extern crate uuid;
 
-use buildchain::{Downloader, Manifest};
-use std::fs;
-use std::path::Path;
+use buildchain::{Downloader, Sha384};
+use serde::Deserialize;
+use std::{env, fmt, fs, thread, time};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Declared first, so that its macros are available to every other module
+#[macro_use]
+mod verbosity;
 
+pub mod changelog;
 pub mod config;
 pub mod download;
 pub mod util;
 
 mod bios;
 mod boot;
+mod bundle;
 mod ec;
+mod esrt;
+mod history;
+mod id;
+mod lock;
 mod me;
+mod models;
 mod mount;
+mod power;
+mod self_test;
+mod status;
+mod system;
 mod thelio_io;
+mod tpm;
+mod whitelist;
 
-pub use bios::bios;
-pub use ec::{ec, ec_or_none};
+pub use buildchain::Manifest;
+pub use bios::{bios, bios_date, virtual_machine};
+pub use boot::{UefiEnvironment, efivars_writable};
+pub use bundle::{BundleComponents, Component, ComponentChange, bundle_components};
+pub use ec::{backup as ec_backup, ec, ec_or_none};
+pub use esrt::{EsrtEntry, capsule_supported, esrt_entries};
+pub use history::{ComponentVersions, HistoryEntry, HistoryOutcome, update_history, update_history_with_reporter};
+pub use id::{FirmwareId, ec_project_from_hash, ec_project_hash};
+pub use models::{ModelDatabase, ModelInfo};
+pub use self_test::{SelfTestResult, self_test};
+pub use status::{StagingStage, StatusReporter, StderrReporter, UpdateStage};
+pub use system::SystemInfo;
+pub use tpm::pcr0;
+pub use verbosity::{Verbosity, set_verbosity, verbosity};
+pub use whitelist::{CompiledWhitelist, FileWhitelist, WhitelistSource};
 pub use me::me;
 pub use thelio_io::{
-    ThelioIo, ThelioIoMetadata,
+    ThelioIo, ThelioIoMetadata, ThelioIoUpdateResult,
     thelio_io_download, thelio_io_list, thelio_io_update
 };
 
-const MODEL_WHITELIST: &[&str] = &[
-    "addw1",
-    "bonw11",
-    "bonw12",
-    "bonw13",
-    "darp5",
-    "darp6",
-    "galp2",
-    "galp3",
-    "galp3-b",
-    "galp3-c",
-    "galp4",
-    "gaze10",
-    "gaze11",
-    "gaze12",
-    "gaze13",
-    "gaze14",
-    "kudu2",
-    "kudu3",
-    "kudu4",
-    "kudu5",
-    "lemu6",
-    "lemu7",
-    "lemu8",
-    "meer4",
-    "orxp1",
-    "oryp2",
-    "oryp2-ess",
-    "oryp3",
-    "oryp3-b",
-    "oryp3-ess",
-    "oryp4",
-    "oryp4-b",
-    "oryp5",
-    "serw9",
-    "serw10",
-    "serw11",
-    "serw11-b",
-    "thelio-b1",
-    "thelio-major-b1",
-    "thelio-major-b1.1",
-    "thelio-major-b2",
-    "thelio-major-r1",
-    "thelio-major-r2",
-    "thelio-mega-b1",
-    "thelio-mega-r1",
-    "thelio-r1",
-];
+// The crate keeps no global state besides a few atomics, so these
+// types may be shared across threads. Assert it, so that a change that breaks
+// this fails to build rather than breaking embedders.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FirmwareId>();
+    assert_send_sync::<SystemInfo>();
+    assert_send_sync::<DownloadOptions>();
+    assert_send_sync::<ScheduleOptions>();
+    assert_send_sync::<ScheduleOutcome>();
+    assert_send_sync::<changelog::Changelog>();
+    assert_send_sync::<HistoryEntry>();
+    assert_send_sync::<StderrReporter>();
+};
+
+const UPDATER_FILE: &str = "system76-firmware-update.tar.xz";
 
+/// The directory on the ESP that the updater is staged in
+const UPDATER_DIR: &str = "system76-firmware-update";
+
+/// Checks a model against the whitelist exactly. A revision or SKU never
+/// matches its base model or another revision: `galp3-c` matches only
+/// `galp3-c`, `oryp3-ess` matches only `oryp3-ess`, and `galp3` matches only
+/// `galp3`, as flashing another revision's firmware could break the board.
 pub fn model_is_whitelisted(model: &str) -> bool {
-    MODEL_WHITELIST
-        .into_iter()
-        .find(|whitelist| model == **whitelist)
-        .is_some()
+    CompiledWhitelist.contains(model)
+}
+
+/// How well a model is supported by firmware updates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ModelSupport {
+    /// The model receives firmware updates.
+    Supported,
+    /// The model can be updated, but no new firmware is expected after
+    /// `last_firmware`.
+    SupportedLegacy { last_firmware: String },
+    /// The model is whitelisted, but the manifest publishes no firmware for it.
+    NotPublished,
+    /// The model is not in the whitelist.
+    NotWhitelisted,
+}
+
+/// What the embedded model database knows about a model, if it is whitelisted.
+pub fn model_info(model: &str) -> Option<ModelInfo> {
+    ModelDatabase::embedded().ok()?.get(model).cloned()
+}
+
+/// The quirks that the embedded model database records for a model.
+pub fn model_quirks(model: &str) -> Vec<String> {
+    model_info(model).map_or(Vec::new(), |info| info.quirks)
+}
+
+pub fn model_support(model: &str) -> ModelSupport {
+    model_support_from(model, &CompiledWhitelist)
+}
+
+/// Like `model_support()`, checking the model against another whitelist.
+pub fn model_support_from(model: &str, whitelist: &dyn WhitelistSource) -> ModelSupport {
+    if ! whitelist.contains(model) {
+        return ModelSupport::NotWhitelisted;
+    }
+
+    match whitelist.legacy(model) {
+        Some(last_firmware) => ModelSupport::SupportedLegacy { last_firmware },
+        None => ModelSupport::Supported,
+    }
+}
+
+/// Like `model_support_from()`, also checking that the manifest publishes
+/// firmware for any EC project of the model.
+pub fn model_support_published(model: &str, whitelist: &dyn WhitelistSource, manifest: &Manifest) -> ModelSupport {
+    let support = model_support_from(model, whitelist);
+    let published = manifest_firmware_ids(manifest).iter().any(|id| id.model() == model);
+    if support == ModelSupport::Supported && ! published {
+        return ModelSupport::NotPublished;
+    }
+    support
 }
 
 // Helper function for errors
@@ -94,24 +158,221 @@ pub fn err_str<E: ::std::fmt::Display>(err: E) -> String {
     format!("{}", err)
 }
 
-pub fn generate_firmware_id(model: &str, project: &str) -> String {
-    let project_hash = util::sha256(project.as_bytes());
-    format!("{}_{}", model, project_hash)
+// Helper function for errors of privileged operations, pointing at missing
+// privileges when that is why the operation failed
+fn io_err_str(operation: &str, err: io::Error) -> String {
+    if err.kind() == io::ErrorKind::PermissionDenied {
+        format!("insufficient privileges to {}, run as root: {}", operation, err)
+    } else {
+        format!("failed to {}: {}", operation, err)
+    }
+}
+
+// Removes the signing key and user names in home paths from a message, so
+// that errors are safe to paste into public bug reports
+fn redact(message: &str) -> String {
+    let message = message.replace(config::KEY, "<key>");
+
+    let mut redacted = String::with_capacity(message.len());
+    let mut rest = message.as_str();
+    while let Some(i) = rest.find("/home/") {
+        let (before, after) = rest.split_at(i + "/home/".len());
+        redacted.push_str(before);
+        redacted.push_str("<user>");
+        rest = &after[after.find('/').unwrap_or(after.len())..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// The name of the firmware bundle for a firmware id in the manifest.
+pub fn firmware_filename(id: &FirmwareId) -> String {
+    format!("{}.tar.xz", id)
+}
+
+/// The name of the updater bundle in the manifest.
+pub fn updater_filename() -> &'static str {
+    UPDATER_FILE
+}
+
+pub fn generate_firmware_id(model: &str, project: &str) -> Result<FirmwareId, String> {
+    FirmwareId::new(model, project)
+}
+
+/// With the `dev` feature, overrides the detected model, so that the download
+/// flow can be exercised for any model from any machine.
+#[cfg(feature = "dev")]
+pub const DEV_MODEL_ENV: &str = "SYSTEM76_FIRMWARE_DEV_MODEL";
+
+/// With the `dev` feature, overrides the detected EC project.
+#[cfg(feature = "dev")]
+pub const DEV_EC_PROJECT_ENV: &str = "SYSTEM76_FIRMWARE_DEV_EC_PROJECT";
+
+#[cfg(feature = "dev")]
+fn dev_override(name: &str) -> Option<String> {
+    let value = env::var(name).ok()?;
+    status!("using {}={:?}", name, value);
+    Some(value)
+}
+
+#[cfg(feature = "dev")]
+fn dev_override_active() -> bool {
+    env::var_os(DEV_MODEL_ENV).is_some() || env::var_os(DEV_EC_PROJECT_ENV).is_some()
+}
+
+/// The model used for the firmware id and the whitelist.
+pub fn current_model() -> Result<String, String> {
+    #[cfg(feature = "dev")]
+    {
+        if let Some(model) = dev_override(DEV_MODEL_ENV) {
+            return Ok(model);
+        }
+    }
+
+    bios::bios().map(|(model, _version)| model)
+}
+
+/// What `firmware_id_with()` does when the EC cannot be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EcPolicy {
+    /// Use `none` as the EC project, as `firmware_id()` does.
+    Lenient,
+    /// Fail, so that nothing is downloaded or flashed based on a guessed id.
+    Strict,
+}
+
+impl Default for EcPolicy {
+    fn default() -> EcPolicy {
+        EcPolicy::Lenient
+    }
+}
+
+pub fn firmware_id() -> Result<FirmwareId, String> {
+    firmware_id_with(EcPolicy::default())
+}
+
+pub fn firmware_id_with(policy: EcPolicy) -> Result<FirmwareId, String> {
+    let bios_model = current_model()?;
+
+    #[cfg(feature = "dev")]
+    {
+        if let Some(ec_project) = dev_override(DEV_EC_PROJECT_ENV) {
+            return generate_firmware_id(&bios_model, &ec_project);
+        }
+    }
+
+    let ec_project = match policy {
+        EcPolicy::Lenient => ec_or_none(true).0,
+        EcPolicy::Strict => match ec::ec(true) {
+            Ok((project, _version)) => project,
+            Err(err) => {
+                return Err(format!("EC project could not be determined: {}", err));
+            }
+        },
+    };
+    generate_firmware_id(&bios_model, &ec_project)
+}
+
+// The updater is the EFI application that performs the flash, so it is checked
+// against its manifest digest explicitly and fails with its own error
+fn verify_updater<R: Read>(reader: R, digest: &str) -> Result<(), String> {
+    let found = Sha384::new(reader).map_err(err_str)?.to_base32();
+    if found != digest {
+        return Err(format!(
+            "updater digest mismatch: expected {}, found {}",
+            digest, found
+        ));
+    }
+
+    Ok(())
+}
+
+/// Holds the digest of the last updater that was scheduled. The name contains
+/// a dot, so that the cache never mistakes it for an object.
+const LAST_UPDATER: &str = "last-updater.digest";
+
+// Remembers the updater of a manifest that was scheduled, for
+// `with_updater_fallback()`
+fn record_updater(manifest: &Manifest) -> io::Result<()> {
+    match manifest.files.get(UPDATER_FILE) {
+        Some(digest) => fs::write(Path::new(config::CACHE).join(LAST_UPDATER), digest),
+        None => Ok(()),
+    }
+}
+
+// Fills in the updater of a manifest that omits it with the last updater that
+// was scheduled. It is verified against its digest when it is opened, like
+// any other updater.
+fn with_updater_fallback(cache: &download::Cache, mut manifest: Manifest, reporter: &dyn StatusReporter) -> Result<Manifest, String> {
+    if manifest.files.contains_key(UPDATER_FILE) {
+        return Ok(manifest);
+    }
+
+    let path = Path::new(config::CACHE).join(LAST_UPDATER);
+    let digest = fs::read_to_string(&path).map_err(|err| {
+        format!("{} not found, and no updater was scheduled before: {}", UPDATER_FILE, err)
+    })?;
+    let digest = digest.trim();
+    if ! cache.contains(digest) {
+        return Err(format!("{} not found, and the last updater {} is no longer cached", UPDATER_FILE, digest));
+    }
+
+    reporter.warning(&format!("{} not found, using the last updater that was scheduled, {}", UPDATER_FILE, digest));
+    manifest.files.insert(UPDATER_FILE.to_string(), digest.to_string());
+    Ok(manifest)
+}
+
+/// The outcome of each detection step behind `firmware_id()`, for triaging
+/// machines that are not detected correctly.
+#[derive(Clone, Debug)]
+pub struct FirmwareIdReport {
+    /// The BIOS model, or the error from reading it.
+    pub bios_model: Result<String, String>,
+    /// The EC project, or the error from reading it.
+    pub ec_project: Result<String, String>,
+    /// The id of this machine, when both the BIOS model and the EC project
+    /// could be read. Unlike `firmware_id()`, this never falls back to the
+    /// `none` project, so that a failure to read the EC is not hidden.
+    pub firmware_id: Option<FirmwareId>,
 }
 
-pub fn firmware_id() -> Result<String, String> {
-    let (bios_model, _bios_version) = bios::bios()?;
-    let (ec_project, _ec_version) = ec_or_none(true);
-    Ok(generate_firmware_id(&bios_model, &ec_project))
+pub fn firmware_id_diagnostic() -> FirmwareIdReport {
+    let bios_model = current_model();
+    let ec_project = ec::ec(true).map(|(project, _version)| project);
+
+    #[cfg(feature = "dev")]
+    let ec_project = match dev_override(DEV_EC_PROJECT_ENV) {
+        Some(project) => Ok(project),
+        None => ec_project,
+    };
+
+    let firmware_id = match (&bios_model, &ec_project) {
+        (Ok(model), Ok(project)) => generate_firmware_id(model, project).ok(),
+        _ => None,
+    };
+
+    FirmwareIdReport {
+        bios_model,
+        ec_project,
+        firmware_id,
+    }
 }
 
-fn remove_dir<P: AsRef<Path>>(path: P) -> Result<(), String> {
-    if path.as_ref().exists() {
-        eprintln!("removing {}", path.as_ref().display());
-        match fs::remove_dir_all(&path) {
+fn remove_dir<P: AsRef<Path>>(path: P, reporter: &dyn StatusReporter) -> Result<(), String> {
+    // A file or symlink left at the path by a broken run is removed as well,
+    // as it would block the rename of the staged directory
+    if let Ok(metadata) = fs::symlink_metadata(&path) {
+        reporter.removing(path.as_ref());
+        let result = if metadata.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match result {
             Ok(()) => (),
             Err(err) => {
-                return Err(format!("failed to remove {}: {}", path.as_ref().display(), err));
+                return Err(io_err_str(&format!("remove {}", path.as_ref().display()), err));
             }
         }
     }
@@ -123,58 +384,373 @@ pub fn download() -> Result<(String, String), String> {
     download_firmware_id(&firmware_id()?)
 }
 
-pub fn download_firmware_id(firmware_id: &str) -> Result<(String, String), String> {
-    let dl = Downloader::new(
+pub fn download_firmware_id(firmware_id: &FirmwareId) -> Result<(String, String), String> {
+    download_with(firmware_id, &DownloadOptions::default())
+}
+
+/// Downloads the firmware referenced by a specific manifest digest instead of
+/// the latest tail, so that a rollout can be locked to a known manifest.
+pub fn download_pinned(digest: &str) -> Result<(String, String), String> {
+    download_pinned_firmware_id(digest, &firmware_id()?)
+}
+
+pub fn download_pinned_firmware_id(digest: &str, firmware_id: &FirmwareId) -> Result<(String, String), String> {
+    download_with(firmware_id, &DownloadOptions {
+        digest: Some(digest.to_string()),
+        ..DownloadOptions::default()
+    })
+}
+
+/// The release channel that firmware is downloaded from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Channel {
+    /// Firmware in general release.
+    Stable,
+    /// Firmware published on another buildchain branch, such as one used for
+    /// testing before general release. The branch is chosen at runtime, so
+    /// testers can opt in without a rebuild.
+    Branch(String),
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel::Stable
+    }
+}
+
+impl Channel {
+    /// The buildchain branch that the channel is published on.
+    pub fn branch(&self) -> &str {
+        match *self {
+            Channel::Stable => config::BRANCH,
+            Channel::Branch(ref branch) => branch,
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Channel::Stable => write!(f, "stable ({})", config::BRANCH),
+            Channel::Branch(ref branch) => write!(f, "branch {}", branch),
+        }
+    }
+}
+
+/// Options for `download_with()`.
+#[derive(Clone, Debug, Default)]
+pub struct DownloadOptions {
+    /// Use this manifest digest instead of the latest tail.
+    pub digest: Option<String>,
+    /// The channel to download the latest tail from. Cached objects are named
+    /// by the digest of their contents, so channels share the cache safely.
+    pub channel: Channel,
+    /// Fetch every object from the server, even if it is already cached.
+    pub force_network: bool,
+    /// If the manifest omits the updater, use the last updater that was
+    /// scheduled, as long as it is still cached. This is off by default, as
+    /// that updater may not match the firmware.
+    pub updater_fallback: bool,
+    /// A PEM certificate to trust for the server instead of the pinned one,
+    /// for a rotated certificate or a proxy that intercepts TLS. Whoever holds
+    /// the key of this certificate can impersonate the server, though objects
+    /// are still checked against the signed manifest.
+    pub cert: Option<PathBuf>,
+}
+
+pub fn download_with(firmware_id: &FirmwareId, options: &DownloadOptions) -> Result<(String, String), String> {
+    download_with_reporter(firmware_id, options, &StderrReporter)
+}
+
+/// Like `download_with()`, sending progress to `reporter` instead of stderr.
+pub fn download_with_reporter(firmware_id: &FirmwareId, options: &DownloadOptions, reporter: &dyn StatusReporter) -> Result<(String, String), String> {
+    let report = download_inner(firmware_id, options, true, reporter)?;
+    Ok((report.digest, report.changelog))
+}
+
+/// What `download_report()` obtained.
+#[derive(Clone, Debug)]
+pub struct DownloadReport {
+    /// The digest of the manifest.
+    pub digest: String,
+    /// The `changelog.json` of the firmware.
+    pub changelog: String,
+    /// Whether the updater was fetched and verified. Scheduling needs it, so
+    /// when it is missing the firmware can be described but not scheduled.
+    pub updater_available: bool,
+}
+
+/// Like `download_with_reporter()`, but only the firmware is required, so that
+/// its changelog can be shown while the updater cannot be fetched.
+pub fn download_report(firmware_id: &FirmwareId, options: &DownloadOptions, reporter: &dyn StatusReporter) -> Result<DownloadReport, String> {
+    download_inner(firmware_id, options, false, reporter)
+}
+
+fn download_inner(firmware_id: &FirmwareId, options: &DownloadOptions, require_updater: bool, reporter: &dyn StatusReporter) -> Result<DownloadReport, String> {
+    reporter.info(&format!("using channel {}", options.channel));
+    let dl = downloader(&options.channel, options.cert.as_deref())?;
+
+    let digest = match options.digest {
+        Some(ref digest) => {
+            reporter.info(&format!("using pinned manifest {}", digest));
+            digest.clone()
+        },
+        None => {
+            reporter.downloading("tail");
+            dl.tail().map_err(|err| redact(&err))?.digest.to_string()
+        }
+    };
+
+    reporter.info("opening download cache");
+    let mut cache = open_cache(Some(dl))?;
+    cache.set_force_network(options.force_network);
+
+    download_manifest(&cache, &digest, firmware_id, options.updater_fallback, require_updater, reporter)
+}
+
+// The cache of a user who is not root, who cannot write to the system cache
+fn user_cache_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(some) => PathBuf::from(some),
+        None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("system76-firmware"))
+}
+
+// Opens the object cache. Root uses the system cache, while other users get
+// a cache of their own that reads through to the system cache, so that
+// unprivileged downloads reuse objects the system already fetched.
+fn open_cache(downloader: Option<Downloader>) -> Result<download::Cache, String> {
+    if unsafe { libc::geteuid() } != 0 {
+        if let Some(dir) = user_cache_dir() {
+            let mut cache = download::Cache::new(dir, downloader)?;
+            cache.set_fallback(config::CACHE);
+            return Ok(cache);
+        }
+    }
+
+    download::Cache::new(config::CACHE, downloader)
+}
+
+/// Fetches the manifest of all files published for the latest tail.
+pub fn fetch_manifest() -> Result<Manifest, String> {
+    let dl = downloader(&Channel::Stable, None)?;
+
+    status!("downloading tail");
+    let tail = dl.tail().map_err(|err| redact(&err))?;
+
+    let cache = open_cache(Some(dl))?;
+
+    status!("downloading manifest.json");
+    load_manifest(&cache, &tail.digest)
+}
+
+/// Lists the firmware ids in a manifest, sorted, skipping files such as the
+/// updater that are not firmware bundles.
+pub fn manifest_firmware_ids(manifest: &Manifest) -> Vec<FirmwareId> {
+    let mut ids = manifest.files.keys().filter_map(|file| {
+        file.strip_suffix(".tar.xz")?.parse::<FirmwareId>().ok()
+    }).collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
+
+/// Lists every firmware id published for a BIOS model in the latest tail, such
+/// as builds for other EC projects or for rolling back.
+pub fn published_ids_for_model(model: &str) -> Result<Vec<FirmwareId>, String> {
+    let manifest = fetch_manifest()?;
+    Ok(manifest_firmware_ids(&manifest).into_iter().filter(|id| id.model() == model).collect())
+}
+
+/// Checks that this machine can be updated at all: its model must be
+/// whitelisted, and firmware must be published for its detected firmware id.
+/// Each failure has its own error, for a setup wizard to explain.
+pub fn validate_machine() -> Result<(), String> {
+    let bios_model = current_model()?;
+    if model_support(&bios_model) == ModelSupport::NotWhitelisted {
+        return Err(format!("{} is not a supported model", bios_model));
+    }
+
+    let manifest = fetch_manifest()?;
+    if model_support_published(&bios_model, &CompiledWhitelist, &manifest) == ModelSupport::NotPublished {
+        return Err(format!("{} is a supported model, but no firmware is published for it", bios_model));
+    }
+
+    let firmware_id = firmware_id()?;
+    if ! manifest.files.contains_key(&firmware_filename(&firmware_id)) {
+        return Err(format!(
+            "{} is a supported model, but no firmware is published for {}",
+            bios_model, firmware_id
+        ));
+    }
+
+    Ok(())
+}
+
+/// The newest manifest format this build understands. Manifests without a
+/// `version` field are treated as version 1.
+pub const SUPPORTED_MANIFEST_VERSION: u64 = 1;
+
+#[derive(Deserialize)]
+struct ManifestVersion {
+    version: Option<u64>,
+}
+
+/// A single hash identifying the exact files of a manifest, for comparing which
+/// build machines received. It covers every file name and digest in sorted
+/// order, so it does not depend on how the manifest was serialized.
+pub fn bundle_revision_hash(manifest: &Manifest) -> String {
+    let mut files = manifest.files.iter().collect::<Vec<_>>();
+    files.sort();
+
+    let mut input = String::new();
+    for (file, digest) in files {
+        input.push_str(file);
+        input.push(' ');
+        input.push_str(digest);
+        input.push('\n');
+    }
+
+    util::sha256(input.as_bytes())
+}
+
+fn load_manifest(cache: &download::Cache, digest: &str) -> Result<Manifest, String> {
+    let manifest_json = cache.object(digest)?;
+
+    // Refuse newer formats rather than risk misreading them
+    let version = serde_json::from_slice::<ManifestVersion>(&manifest_json)
+        .map_err(|e| e.to_string())?
+        .version
+        .unwrap_or(1);
+    if version > SUPPORTED_MANIFEST_VERSION {
+        return Err(format!(
+            "unsupported manifest version {}, this build supports up to {}",
+            version, SUPPORTED_MANIFEST_VERSION
+        ));
+    }
+
+    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(|err| {
+        format!("failed to parse manifest {}: {}", digest, err)
+    })?;
+
+    // Otherwise every file would be reported missing on its own
+    if manifest.files.is_empty() {
+        return Err(format!("manifest {} lists no files, it was not published correctly", digest));
+    }
+
+    Ok(manifest)
+}
+
+fn downloader(channel: &Channel, cert: Option<&Path>) -> Result<Downloader, String> {
+    let custom_cert = match cert {
+        Some(path) => Some(fs::read(path).map_err(|err| {
+            format!("failed to read certificate {}: {}", path.display(), err)
+        })?),
+        None => None,
+    };
+
+    Downloader::new(
         config::KEY,
         config::URL,
         config::PROJECT,
-        config::BRANCH,
-        Some(config::CERT)
-    )?;
+        channel.branch(),
+        Some(custom_cert.as_deref().unwrap_or(config::CERT))
+    ).map_err(|err| redact(&err))
+}
 
-    eprintln!("downloading tail");
-    let tail = dl.tail()?;
+// Fetches a file of the manifest into `objects`, returning its digest. Files
+// listed in the manifest were published, so failing to fetch one points at the
+// server rather than at a missing file. Files can share an object, so each
+// digest is fetched once.
+fn fetch_object<'a>(cache: &download::Cache, manifest: &'a Manifest, objects: &mut BTreeMap<&'a str, Vec<u8>>, file: &str, reporter: &dyn StatusReporter) -> Result<&'a str, String> {
+    let digest = manifest.files.get(file).ok_or(format!("{} not found", file))?;
+    if objects.contains_key(digest.as_str()) {
+        verbose!("{} shares object {} with a file already fetched", file, digest);
+        return Ok(digest);
+    }
 
-    eprintln!("opening download cache");
-    let cache = download::Cache::new(config::CACHE, Some(dl))?;
+    throttle(reporter);
+    reporter.downloading(file);
+    let data = cache.fetch(digest).map_err(|err| {
+        format!("failed to fetch {} object {}: {}", file, digest, err)
+    })?;
+    objects.insert(digest, data);
+    Ok(digest)
+}
 
-    eprintln!("downloading manifest.json");
-    let manifest_json = cache.object(&tail.digest)?;
-    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(|e| e.to_string())?;
+fn download_manifest(cache: &download::Cache, digest: &str, firmware_id: &FirmwareId, updater_fallback: bool, require_updater: bool, reporter: &dyn StatusReporter) -> Result<DownloadReport, String> {
+    reporter.update_stage(UpdateStage::Download);
+    reporter.downloading("manifest.json");
+    let mut manifest = load_manifest(cache, digest)?;
+    if updater_fallback {
+        manifest = with_updater_fallback(cache, manifest, reporter)?;
+    }
 
-    let _updater_data = {
-        let file = "system76-firmware-update.tar.xz";
-        eprintln!("downloading {}", file);
-        let digest = manifest.files.get(file).ok_or(format!("{} not found", file))?;
-        cache.object(&digest)?
-    };
+    let mut objects = BTreeMap::new();
 
-    let firmware_data = {
-        let file = format!("{}.tar.xz", firmware_id);
-        eprintln!("downloading {}", file);
-        let digest = manifest.files.get(&file).ok_or(format!("{} not found", file))?;
-        cache.object(&digest)?
+    // Only a missing updater is tolerated. One that does not match its digest
+    // always fails, however the firmware is downloaded.
+    let updater_digest = match fetch_object(cache, &manifest, &mut objects, UPDATER_FILE, reporter) {
+        Ok(ok) => Some(ok),
+        Err(err) => {
+            if require_updater {
+                return Err(err);
+            }
+            reporter.warning(&format!("the updater is not available, so the firmware cannot be scheduled: {}", err));
+            None
+        }
     };
 
-    eprintln!("loading changelog.json");
-    let changelog = util::extract_file(&firmware_data, "./changelog.json").map_err(err_str)?;
+    let firmware_digest = fetch_object(cache, &manifest, &mut objects, &firmware_filename(firmware_id), reporter)?;
+
+    // Every object is downloaded by now, so verifying starts only here
+    reporter.update_stage(UpdateStage::Verify);
+    if let Some(updater_digest) = updater_digest {
+        verify_updater(objects[updater_digest].as_slice(), updater_digest)?;
+    }
+    let updater_available = updater_digest.is_some();
+
+    reporter.info("loading changelog.json");
+    let changelog = util::extract_file(objects[firmware_digest].as_slice(), "./changelog.json").map_err(err_str)?;
 
-    Ok((tail.digest.to_string(), changelog))
+    Ok(DownloadReport {
+        digest: digest.to_string(),
+        changelog,
+        updater_available,
+    })
 }
 
-fn extract<P: AsRef<Path>>(digest: &str, file: &str, path: P) -> Result<(), String> {
-    let cache = download::Cache::new(config::CACHE, None)?;
+// Opens a file of the manifest from the cache for streaming
+fn manifest_file(cache: &download::Cache, manifest: &Manifest, file: &str) -> Result<fs::File, String> {
+    let digest = manifest.files.get(file).ok_or(format!("{} not found", file))?;
+    let mut reader = cache.open(&digest)?;
+    if file == UPDATER_FILE {
+        verify_updater(&mut reader, digest)?;
+        reader.seek(SeekFrom::Start(0)).map_err(err_str)?;
+    }
+    Ok(reader)
+}
 
-    let manifest_json = cache.object(&digest)?;
-    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(|e| e.to_string())?;
+/// How long to pause each time the reporter asks to throttle
+const THROTTLE_PAUSE: time::Duration = time::Duration::from_millis(100);
 
-    let data = {
-        let digest = manifest.files.get(file).ok_or(format!("{} not found", file))?;
-        cache.object(&digest)?
-    };
+// Pauses for as long as the reporter asks to throttle
+fn throttle(reporter: &dyn StatusReporter) {
+    while reporter.throttle() {
+        thread::sleep(THROTTLE_PAUSE);
+    }
+}
+
+fn extract<P: AsRef<Path>>(cache: &download::Cache, manifest: &Manifest, file: &str, path: P, reporter: &dyn StatusReporter) -> Result<(), String> {
+    let reader = manifest_file(cache, manifest, file)?;
 
-    eprintln!("extracting {} to {}", file, path.as_ref().display());
-    match util::extract(&data, &path) {
+    reporter.extracting(file, path.as_ref());
+    let progress = |entry_path: &Path| {
+        reporter.extracted(entry_path);
+        throttle(reporter);
+    };
+    match util::extract_with_progress(reader, &path, reporter, progress) {
         Ok(()) => (),
         Err(err) => {
             return Err(format!("failed to extract {} to {}: {}", file, path.as_ref().display(), err));
@@ -184,59 +760,1504 @@ fn extract<P: AsRef<Path>>(digest: &str, file: &str, path: P) -> Result<(), Stri
     Ok(())
 }
 
-pub fn schedule(digest: &str, efi_dir: &str) -> Result<(), String> {
-    schedule_firmware_id(digest, efi_dir, &firmware_id()?)
+/// Re-verifies every object in the cache against its digest, deleting corrupt
+/// objects if `delete` is set. This never uses the network.
+pub fn cache_verify(delete: bool) -> Result<download::VerifyReport, String> {
+    let cache = download::Cache::new(config::CACHE, None)?;
+    cache.verify(delete)
+}
+
+// Opens a file of the manifest from the cache, without using the network
+fn cached_file(digest: &str, file: &str) -> Result<fs::File, String> {
+    let cache = open_cache(None)?;
+
+    let manifest = load_manifest(&cache, digest)?;
+
+    manifest_file(&cache, &manifest, file)
+}
+
+fn firmware_changelog(cache: &download::Cache, manifest: &Manifest, firmware_id: &FirmwareId) -> Result<changelog::Changelog, String> {
+    let file = firmware_filename(firmware_id);
+    let reader = manifest_file(cache, manifest, &file)?;
+
+    let json = util::extract_file(reader, "./changelog.json").map_err(|err| {
+        format!("failed to extract changelog.json from {}: {}", file, err)
+    })?;
+
+    changelog::Changelog::parse(&json)
 }
 
-pub fn schedule_firmware_id(digest: &str, efi_dir: &str, firmware_id: &str) -> Result<(), String> {
-    if ! Path::new("/sys/firmware/efi").exists() {
-        return Err(format!("must be run using UEFI boot"));
+/// The language of `changelog.json`, which every bundle ships.
+pub const DEFAULT_NOTES_LANG: &str = "en";
+
+/// Lists the languages of the release notes in a cached bundle, from any
+/// `changelog.<lang>.json` it ships besides the English `changelog.json`.
+pub fn firmware_notes_languages(digest: &str, firmware_id: &FirmwareId) -> Result<Vec<String>, String> {
+    let file = firmware_filename(firmware_id);
+    let files = util::list_files(cached_file(digest, &file)?).map_err(|err| {
+        format!("failed to list {}: {}", file, err)
+    })?;
+
+    let mut langs = vec![DEFAULT_NOTES_LANG.to_string()];
+    for path in files {
+        if let Some(name) = path.to_str() {
+            if let Some(lang) = name.strip_prefix("changelog.").and_then(|x| x.strip_suffix(".json")) {
+                if ! lang.is_empty() && ! lang.contains('/') {
+                    langs.push(lang.to_string());
+                }
+            }
+        }
     }
+    langs.sort();
+    langs.dedup();
 
-    let updater_file = "system76-firmware-update.tar.xz";
-    let firmware_file = format!("{}.tar.xz", firmware_id);
-    let updater_dir = Path::new(efi_dir).join("system76-firmware-update");
+    Ok(langs)
+}
+
+/// Reads the release notes of a cached bundle in the given language, falling
+/// back to English when the bundle has no notes in that language.
+pub fn firmware_notes_lang(digest: &str, firmware_id: &FirmwareId, lang: &str) -> Result<changelog::Changelog, String> {
+    let file = firmware_filename(firmware_id);
+
+    if lang != DEFAULT_NOTES_LANG {
+        let path = format!("./changelog.{}.json", lang);
+        match util::extract_file(cached_file(digest, &file)?, &path) {
+            Ok(json) => return changelog::Changelog::parse(&json),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                status!("{} has no release notes in {}, using {}", file, lang, DEFAULT_NOTES_LANG);
+            },
+            Err(err) => {
+                return Err(format!("failed to extract {} from {}: {}", path, file, err));
+            }
+        }
+    }
 
-    boot::unset_next_boot()?;
+    let json = util::extract_file(cached_file(digest, &file)?, "./changelog.json").map_err(|err| {
+        format!("failed to extract changelog.json from {}: {}", file, err)
+    })?;
 
-    remove_dir(&updater_dir)?;
+    changelog::Changelog::parse(&json)
+}
 
-    let updater_tmp = match tempdir::TempDir::new_in(efi_dir, "system76-firmware-update") {
-        Ok(ok) => ok,
+// Checks that the staged directory holds what booting the updater relies on,
+// catching a malformed publish before the boot entry is set
+fn validate_staged(path: &Path) -> Result<(), String> {
+    let loader = path.join("boot.efi");
+    match fs::metadata(&loader) {
+        Ok(metadata) => if metadata.len() == 0 {
+            return Err(format!("bundle validation failed: {} is empty", loader.display()));
+        },
         Err(err) => {
-            return Err(format!("failed to create temporary directory: {}", err));
+            return Err(format!("bundle validation failed: {}: {}", loader.display(), err));
         }
-    };
+    }
+
+    let firmware_dir = path.join("firmware");
+    let files = util::list_dir(&firmware_dir).map_err(|err| {
+        format!("bundle validation failed: {}: {}", firmware_dir.display(), err)
+    })?;
+    if ! files.iter().any(|&(_, size)| size > 0) {
+        return Err(format!("bundle validation failed: {} has no firmware", firmware_dir.display()));
+    }
 
-    extract(digest, updater_file, updater_tmp.path())?;
+    Ok(())
+}
 
-    extract(digest, &firmware_file, &updater_tmp.path().join("firmware"))?;
+pub fn schedule(digest: &str, efi_dir: &str) -> Result<(), String> {
+    schedule_firmware_id(digest, efi_dir, &firmware_id()?)
+}
 
-    let updater_tmp_dir = updater_tmp.into_path();
-    eprintln!("moving {} to {}", updater_tmp_dir.display(), updater_dir.display());
-    match fs::rename(&updater_tmp_dir, &updater_dir) {
-        Ok(()) => (),
+pub fn schedule_firmware_id(digest: &str, efi_dir: &str, firmware_id: &FirmwareId) -> Result<(), String> {
+    schedule_reported(digest, efi_dir, firmware_id, &ScheduleOptions::default())?;
+    Ok(())
+}
+
+/// Stages the firmware of a given id rather than the one detected from this
+/// machine, for preparing a machine that was identified earlier. Flashing the
+/// firmware of another model can break the machine, so this fails unless
+/// `acknowledged` is set, and the id must be published in the manifest. When
+/// the id is not this machine's, the installed versions say nothing about the
+/// bundle, so it is staged as if `options.force` were set.
+pub fn schedule_for_id(digest: &str, efi_dir: &str, firmware_id: &FirmwareId, acknowledged: bool, options: &ScheduleOptions, reporter: &dyn StatusReporter) -> Result<ScheduleOutcome, ScheduleError> {
+    if ! acknowledged {
+        return Err(ScheduleError::Refused(format!(
+            "scheduling firmware for {} instead of the detected hardware must be acknowledged",
+            firmware_id
+        )));
+    }
+
+    let cache = open_cache(None).map_err(ScheduleError::LoadManifest)?;
+    let manifest = load_manifest(&cache, digest).map_err(ScheduleError::LoadManifest)?;
+    if ! manifest.files.contains_key(&firmware_filename(firmware_id)) {
+        return Err(ScheduleError::Refused(format!("{} is not published in manifest {}", firmware_id, digest)));
+    }
+
+    let foreign = match self::firmware_id() {
+        Ok(ref detected) if detected == firmware_id => false,
+        Ok(detected) => {
+            reporter.warning(&format!(
+                "scheduling firmware for {}, but this machine is {}",
+                firmware_id, detected
+            ));
+            true
+        },
         Err(err) => {
-            let _ = remove_dir(&updater_tmp_dir);
-            return Err(format!("failed to move {} to {}: {}", updater_tmp_dir.display(), updater_dir.display(), err));
+            reporter.warning(&format!(
+                "scheduling firmware for {}, but this machine could not be identified: {}",
+                firmware_id, err
+            ));
+            true
+        },
+    };
+
+    if foreign {
+        let options = ScheduleOptions {
+            force: true,
+            ..options.clone()
+        };
+        schedule_with_reporter(digest, efi_dir, firmware_id, &options, reporter)
+    } else {
+        schedule_with_reporter(digest, efi_dir, firmware_id, options, reporter)
+    }
+}
+
+/// Options for `schedule_reported()`.
+#[derive(Clone, Debug, Default)]
+pub struct ScheduleOptions {
+    /// Checked at each safe point while scheduling. Once set, the operation is
+    /// wound back: staged files are removed and `BootNext` is left unset. The
+    /// crate never installs signal handlers itself, so an application wanting
+    /// Ctrl-C to cancel should set this flag from its own handler.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Stage the update even if the installed firmware already matches it.
+    pub force: bool,
+    /// When set, the updater and firmware tarballs and the manifest that were
+    /// staged are copied to this directory, so the exact artifacts can be
+    /// handed to support.
+    pub keep_artifacts: Option<PathBuf>,
+    /// If the manifest omits the updater, use the last updater that was
+    /// scheduled, as with `DownloadOptions::updater_fallback`.
+    pub updater_fallback: bool,
+    /// Other ESPs that the staged updater is copied to, such as the members of
+    /// a mirrored boot setup, so that it is found whichever ESP the firmware
+    /// boots from. `BootNext` still points at the entry on the ESP passed to
+    /// scheduling, and `unschedule()` removes only the updater there, so the
+    /// copies of a cancelled update are left until the next schedule.
+    pub mirror_esps: Vec<PathBuf>,
+}
+
+impl ScheduleOptions {
+    fn check_cancel(&self) -> Result<(), ScheduleError> {
+        if self.cancel.as_ref().map_or(false, |cancel| cancel.load(Ordering::SeqCst)) {
+            return Err(ScheduleError::Cancelled);
         }
+
+        Ok(())
     }
+}
+
+/// A record of what `schedule_reported()` wrote to the ESP, for audit logging.
+#[derive(Clone, Debug)]
+pub struct ScheduleReport {
+    /// The firmware id that was staged.
+    pub firmware_id: FirmwareId,
+    /// The staged files and their sizes in bytes, sorted by path.
+    pub files: Vec<(PathBuf, u64)>,
+    /// The boot entry that `BootNext` was set to.
+    pub boot_next: u16,
+}
 
-    boot::set_next_boot(efi_dir)?;
+/// The result of `schedule_reported()`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ScheduleOutcome {
+    /// The update was staged and will be installed on the next boot.
+    Scheduled(ScheduleReport),
+    /// The installed firmware already matches the bundle, so nothing was staged.
+    AlreadyUpToDate,
+}
 
-    eprintln!("Firmware update scheduled. Reboot your machine to install.");
+// Compares the installed BIOS and ME versions against the newest version in the
+// bundle's changelog. The changelog does not describe the EC version, so it is
+// not considered. A version that cannot be read is never up to date, so that
+// the update is still staged.
+fn is_up_to_date(changelog: &changelog::Changelog, system: &SystemInfo) -> bool {
+    let latest = match changelog.latest() {
+        Some(some) => some,
+        None => return false,
+    };
 
-    Ok(())
+    match system.bios {
+        Ok((_, ref bios_version)) if latest.bios == *bios_version => (),
+        _ => return false,
+    }
+
+    if let Some(ref me_version) = latest.me {
+        match system.me {
+            Ok(Some(ref installed)) if installed == me_version => (),
+            _ => return false,
+        }
+    }
+
+    true
 }
 
-pub fn unschedule(efi_dir: &str) -> Result<(), String> {
-    let updater_dir = Path::new(efi_dir).join("system76-firmware-update");
+/// Below this battery charge in percent, scheduling warns that the machine may
+/// not have enough power to finish flashing.
+pub const LOW_BATTERY_PERCENT: u8 = 30;
 
-    boot::unset_next_boot()?;
+/// A pre-flight condition that does not stop `try_schedule()`, but that the
+/// caller may want to surface before the user reboots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// AC power is not connected.
+    OnBattery,
+    /// The battery charge is below `LOW_BATTERY_PERCENT`.
+    LowBattery { percent: u8 },
+}
 
-    remove_dir(&updater_dir)?;
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::OnBattery => write!(f, "AC power is not connected"),
+            Warning::LowBattery { percent } => write!(f, "battery is at {}%", percent),
+        }
+    }
+}
 
-    eprintln!("Firmware update cancelled.");
+fn preflight_warnings() -> Vec<Warning> {
+    let mut warnings = Vec::new();
 
-    Ok(())
+    if power::ac_online() == Some(false) {
+        warnings.push(Warning::OnBattery);
+    }
+
+    if let Some(percent) = power::battery_capacity() {
+        if percent < LOW_BATTERY_PERCENT {
+            warnings.push(Warning::LowBattery { percent });
+        }
+    }
+
+    warnings
+}
+
+/// Where scheduling failed, so that a front-end can suggest a remedy for the
+/// step and a retry knows where it stopped. Each variant displays the same
+/// message that scheduling always reported.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ScheduleError {
+    /// The system was not booted with UEFI.
+    #[error(display = "must be run using UEFI boot")]
+    NotUefi,
+    /// efivarfs is mounted read-only, so `BootNext` cannot be set.
+    #[error(display = "efivarfs is mounted read-only, so the boot entry cannot be set")]
+    EfivarsReadOnly,
+    /// Nothing is mounted at the ESP directory.
+    #[error(display = "{} is not a mount point, expected the ESP to be mounted there", _0)]
+    EspNotMounted(String),
+    /// This machine or bundle may not be scheduled, such as in a virtual
+    /// machine.
+    #[error(display = "{}", _0)]
+    Refused(String),
+    /// The ESP is full, mounted read-only or not writable by this user.
+    #[error(display = "{}", _0)]
+    EspNotWritable(String),
+    /// fwupd has an update pending.
+    #[error(display = "{}", _0)]
+    ConflictingUpdate(String),
+    /// A previous `BootNext` could not be unset.
+    #[error(display = "{}", _0)]
+    UnsetBoot(String),
+    /// A previously staged update could not be removed.
+    #[error(display = "{}", _0)]
+    RemoveDir(String),
+    /// The temporary staging directory could not be created.
+    #[error(display = "{}", _0)]
+    CreateTemp(String),
+    /// The updater could not be extracted.
+    #[error(display = "{}", _0)]
+    ExtractUpdater(String),
+    /// The firmware could not be extracted.
+    #[error(display = "{}", _0)]
+    ExtractFirmware(String),
+    /// The staged files are incomplete.
+    #[error(display = "{}", _0)]
+    InvalidStaged(String),
+    /// The staged directory could not be moved into place.
+    #[error(display = "{}", _0)]
+    Rename(String),
+    /// The staged directory could not be copied to a mirror ESP.
+    #[error(display = "{}", _0)]
+    Mirror(String),
+    /// `BootNext` could not be set.
+    #[error(display = "{}", _0)]
+    SetBoot(String),
+    /// The cancel flag of `ScheduleOptions` was set.
+    #[error(display = "scheduling cancelled")]
+    Cancelled,
+    /// The schedule lock could not be taken, usually because another process
+    /// is already scheduling or cancelling an update.
+    #[error(display = "{}", _0)]
+    AlreadyRunning(String),
+    /// The cache, the manifest or the bundle's changelog could not be loaded.
+    #[error(display = "{}", _0)]
+    LoadManifest(String),
+    /// Anything else, such as listing the staged files.
+    #[error(display = "{}", _0)]
+    Other(String),
+}
+
+impl From<String> for ScheduleError {
+    fn from(err: String) -> ScheduleError {
+        ScheduleError::Other(err)
+    }
+}
+
+impl From<ScheduleError> for String {
+    fn from(err: ScheduleError) -> String {
+        err.to_string()
+    }
+}
+
+/// Like `schedule_reported()`, but also returns the non-fatal warnings found
+/// before scheduling, such as running on battery. Warnings never stop the
+/// update from being staged, while errors still do. Each warning is also sent
+/// to `reporter`, along with the progress of scheduling.
+pub fn try_schedule(digest: &str, efi_dir: &str, firmware_id: &FirmwareId, options: &ScheduleOptions, reporter: &dyn StatusReporter) -> Result<(ScheduleOutcome, Vec<Warning>), ScheduleError> {
+    let warnings = preflight_warnings();
+    for warning in &warnings {
+        reporter.warning(&warning.to_string());
+    }
+
+    let outcome = schedule_with_reporter(digest, efi_dir, firmware_id, options, reporter)?;
+    Ok((outcome, warnings))
+}
+
+/// Stages the update and records the attempt in the update history.
+pub fn schedule_reported(digest: &str, efi_dir: &str, firmware_id: &FirmwareId, options: &ScheduleOptions) -> Result<ScheduleOutcome, ScheduleError> {
+    schedule_with_reporter(digest, efi_dir, firmware_id, options, &StderrReporter)
+}
+
+/// Like `schedule_reported()`, sending progress to `reporter` instead of stderr.
+pub fn schedule_with_reporter(digest: &str, efi_dir: &str, firmware_id: &FirmwareId, options: &ScheduleOptions, reporter: &dyn StatusReporter) -> Result<ScheduleOutcome, ScheduleError> {
+    schedule_steps(&HostSteps, digest, efi_dir, firmware_id, options, reporter)
+}
+
+fn schedule_steps(steps: &dyn ScheduleSteps, digest: &str, efi_dir: &str, firmware_id: &FirmwareId, options: &ScheduleOptions, reporter: &dyn StatusReporter) -> Result<ScheduleOutcome, ScheduleError> {
+    let _lock = steps.lock().map_err(ScheduleError::AlreadyRunning)?;
+
+    // The installed firmware is read once and shared by every step
+    let system = steps.system();
+    let from = system.versions();
+    let mut to = ComponentVersions::default();
+    let mut revision = None;
+
+    // The manifest and changelog are loaded once and shared by every step
+    let result = steps.open_cache().map_err(ScheduleError::LoadManifest).and_then(|cache| {
+        let mut manifest = load_manifest(&cache, digest).map_err(ScheduleError::LoadManifest)?;
+        revision = Some(bundle_revision_hash(&manifest));
+        if options.updater_fallback {
+            manifest = with_updater_fallback(&cache, manifest, reporter).map_err(ScheduleError::LoadManifest)?;
+        }
+        let changelog = firmware_changelog(&cache, &manifest, firmware_id).map_err(ScheduleError::LoadManifest)?;
+        to = ComponentVersions::from_changelog(&changelog);
+        let outcome = schedule_inner(steps, &cache, &manifest, &changelog, &system, efi_dir, firmware_id, options, reporter)?;
+        if let ScheduleOutcome::Scheduled(_) = outcome {
+            if let Err(err) = record_updater(&manifest) {
+                reporter.warning(&format!("failed to record the updater that was scheduled: {}", err));
+            }
+        }
+        if let (&ScheduleOutcome::Scheduled(_), Some(ref dir)) = (&outcome, &options.keep_artifacts) {
+            // The update is already scheduled, so this is not worth failing over
+            if let Err(err) = keep_artifacts(&cache, digest, &manifest, firmware_id, dir, reporter) {
+                reporter.warning(&format!("failed to keep artifacts in {}: {}", dir.display(), err));
+            }
+        }
+        Ok(outcome)
+    });
+
+    let outcome = match result {
+        Ok(ScheduleOutcome::Scheduled(_)) => HistoryOutcome::Scheduled,
+        Ok(ScheduleOutcome::AlreadyUpToDate) => HistoryOutcome::AlreadyUpToDate,
+        Err(ref err) => HistoryOutcome::Failed(err.to_string()),
+    };
+    let mut entry = HistoryEntry::new(firmware_id.to_string(), digest.to_string(), from, to, outcome);
+    entry.revision = revision;
+    entry.pcr0 = tpm::pcr0();
+    if let Err(err) = steps.record(entry, reporter) {
+        reporter.warning(&format!("failed to record update history: {}", err));
+    }
+
+    result
+}
+
+// Copies the staged tarballs and the manifest out of the cache
+fn keep_artifacts(cache: &download::Cache, digest: &str, manifest: &Manifest, firmware_id: &FirmwareId, dir: &Path, reporter: &dyn StatusReporter) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(err_str)?;
+
+    let manifest_json = cache.object(digest)?;
+    fs::write(dir.join("manifest.json"), &manifest_json).map_err(err_str)?;
+
+    for file in &[UPDATER_FILE.to_string(), firmware_filename(firmware_id)] {
+        let mut reader = manifest_file(cache, manifest, file)?;
+        let mut writer = fs::File::create(dir.join(file)).map_err(err_str)?;
+        io::copy(&mut reader, &mut writer).map_err(err_str)?;
+    }
+
+    reporter.info(&format!("kept artifacts in {}", dir.display()));
+
+    Ok(())
+}
+
+// Refuses to stage a revision that was already scheduled and has since been
+// flashed, which the installed versions having changed since it was scheduled
+// shows. This stops automation from looping on one update when the changelog
+// does not describe the installed versions exactly.
+fn check_not_applied(manifest: &Manifest, system: &SystemInfo, firmware_id: &FirmwareId, reporter: &dyn StatusReporter) -> Result<(), String> {
+    let last = match history::last_scheduled(Path::new(config::CACHE), firmware_id.as_str(), reporter) {
+        Ok(Some(some)) => some,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            reporter.warning(&format!("failed to read update history: {}", err));
+            return Ok(());
+        }
+    };
+
+    let revision = bundle_revision_hash(manifest);
+    if last.revision.as_ref() == Some(&revision) && last.from != system.versions() {
+        return Err(format!(
+            "revision {} was already applied, use force to schedule it again",
+            revision
+        ));
+    }
+
+    Ok(())
+}
+
+// Creates and removes a small file in the ESP, so that an ESP that is full or
+// mounted read-only fails early rather than after the old update was removed
+fn check_writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(".system76-firmware-probe");
+    let result = fs::File::create(&probe).and_then(|mut file| {
+        io::Write::write_all(&mut file, b"probe")?;
+        file.sync_all()
+    });
+    let _ = fs::remove_file(&probe);
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => if err.kind() == io::ErrorKind::PermissionDenied {
+            Err(io_err_str(&format!("write to {}", dir.display()), err))
+        } else {
+            Err(format!(
+                "{} is not writable, it may be full or mounted read-only: {}",
+                dir.display(), err
+            ))
+        },
+    }
+}
+
+// Finds capsules that fwupd staged on the ESP. fwupd stages each update as a
+// `.cap` file in the `fw` directory next to its EFI application, such as
+// `EFI/ubuntu/fw`, and removes it once the update was attempted. This is
+// best-effort: anything that cannot be read counts as no conflict.
+fn fwupd_capsules(efi_dir: &Path) -> Vec<PathBuf> {
+    let mut capsules = Vec::new();
+
+    let vendors = match fs::read_dir(efi_dir.join("EFI")) {
+        Ok(ok) => ok,
+        Err(_) => return capsules,
+    };
+
+    for vendor in vendors.filter_map(|entry| entry.ok()) {
+        let fw = match fs::read_dir(vendor.path().join("fw")) {
+            Ok(ok) => ok,
+            Err(_) => continue,
+        };
+        for entry in fw.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.extension().map_or(false, |extension| extension == "cap") {
+                capsules.push(path);
+            }
+        }
+    }
+
+    capsules.sort();
+    capsules
+}
+
+// Refuses to stage an update while fwupd has one pending, as both set
+// `BootNext` and would clobber each other
+fn check_no_conflicting_update(efi_dir: &Path) -> Result<(), String> {
+    let capsules = fwupd_capsules(efi_dir);
+    if let Some(capsule) = capsules.first() {
+        return Err(format!(
+            "fwupd has an update pending in {}, reboot to apply it or cancel it with fwupdmgr before scheduling",
+            capsule.display()
+        ));
+    }
+
+    Ok(())
+}
+
+// Removes a staged directory when dropped, so that an early return or a panic
+// between leaving the temporary directory and setting the boot entry leaves
+// nothing half staged on the ESP
+struct StagedDir(Option<PathBuf>);
+
+impl StagedDir {
+    fn path(&self) -> &Path {
+        self.0.as_ref().expect("staged directory already committed")
+    }
+
+    fn commit(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for StagedDir {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.0 {
+            let _ = fs::remove_dir_all(path);
+        }
+    }
+}
+
+// Copies a staged directory to another ESP, moving it into place the same way
+fn mirror_staged(updater_dir: &Path, esp: &Path, reporter: &dyn StatusReporter) -> Result<StagedDir, String> {
+    check_writable(esp)?;
+
+    let mirror_dir = esp.join(UPDATER_DIR);
+    remove_dir(&mirror_dir, reporter)?;
+
+    let mirror_tmp = match tempdir::TempDir::new_in(esp, UPDATER_DIR) {
+        Ok(ok) => ok,
+        Err(err) => {
+            return Err(io_err_str("create temporary directory", err));
+        }
+    };
+    let mut staged = StagedDir(Some(mirror_tmp.into_path()));
+    let mirror_tmp_dir = staged.path().to_owned();
+
+    if let Err(err) = util::copy_dir(updater_dir, &mirror_tmp_dir) {
+        return Err(io_err_str(&format!("copy {} to {}", updater_dir.display(), mirror_tmp_dir.display()), err));
+    }
+
+    reporter.moving(&mirror_tmp_dir, &mirror_dir);
+    if let Err(err) = fs::rename(&mirror_tmp_dir, &mirror_dir) {
+        return Err(io_err_str(&format!("move {} to {}", mirror_tmp_dir.display(), mirror_dir.display()), err));
+    }
+    staged.0 = Some(mirror_dir);
+
+    Ok(staged)
+}
+
+// The steps of scheduling that touch the machine, each defaulting to what
+// scheduling does on the host, so that tests can make each step fail in turn
+trait ScheduleSteps {
+    fn lock(&self) -> Result<lock::ScheduleLock, String> {
+        lock::ScheduleLock::acquire()
+    }
+
+    fn open_cache(&self) -> Result<download::Cache, String> {
+        open_cache(None)
+    }
+
+    fn system(&self) -> SystemInfo {
+        SystemInfo::read()
+    }
+
+    fn record(&self, entry: HistoryEntry, reporter: &dyn StatusReporter) -> Result<(), String> {
+        history::append(Path::new(config::CACHE), entry, reporter)
+    }
+
+    fn environment(&self, efi_dir: &str) -> boot::UefiEnvironment {
+        boot::UefiEnvironment::detect(efi_dir)
+    }
+
+    fn virtual_machine(&self) -> Option<&'static str> {
+        bios::virtual_machine()
+    }
+
+    fn check_writable(&self, dir: &Path) -> Result<(), String> {
+        check_writable(dir)
+    }
+
+    fn check_no_conflicting_update(&self, efi_dir: &Path) -> Result<(), String> {
+        check_no_conflicting_update(efi_dir)
+    }
+
+    fn unset_next_boot(&self, reporter: &dyn StatusReporter) -> Result<(), String> {
+        boot::unset_next_boot(reporter)
+    }
+
+    fn remove_dir(&self, path: &Path, reporter: &dyn StatusReporter) -> Result<(), String> {
+        remove_dir(path, reporter)
+    }
+
+    fn create_temp(&self, efi_dir: &str) -> io::Result<tempdir::TempDir> {
+        tempdir::TempDir::new_in(efi_dir, UPDATER_DIR)
+    }
+
+    fn extract(&self, cache: &download::Cache, manifest: &Manifest, file: &str, path: &Path, reporter: &dyn StatusReporter) -> Result<(), String> {
+        extract(cache, manifest, file, path, reporter)
+    }
+
+    fn validate_staged(&self, path: &Path) -> Result<(), String> {
+        validate_staged(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn list_dir(&self, path: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+        util::list_dir(path)
+    }
+
+    fn mirror_staged(&self, updater_dir: &Path, esp: &Path, reporter: &dyn StatusReporter) -> Result<StagedDir, String> {
+        mirror_staged(updater_dir, esp, reporter)
+    }
+
+    fn set_next_boot(&self, efi_dir: &str, reporter: &dyn StatusReporter) -> Result<(), String> {
+        boot::set_next_boot(efi_dir, reporter)
+    }
+}
+
+struct HostSteps;
+
+impl ScheduleSteps for HostSteps {}
+
+#[allow(clippy::too_many_arguments)]
+fn schedule_inner(
+    steps: &dyn ScheduleSteps,
+    cache: &download::Cache,
+    manifest: &Manifest,
+    changelog: &changelog::Changelog,
+    system: &SystemInfo,
+    efi_dir: &str,
+    firmware_id: &FirmwareId,
+    options: &ScheduleOptions,
+    reporter: &dyn StatusReporter
+) -> Result<ScheduleOutcome, ScheduleError> {
+    let environment = steps.environment(efi_dir);
+    if ! environment.booted_uefi {
+        return Err(ScheduleError::NotUefi);
+    }
+    if environment.efivars_writable == Some(false) {
+        return Err(ScheduleError::EfivarsReadOnly);
+    }
+    if environment.esp_mount.is_none() {
+        return Err(ScheduleError::EspNotMounted(efi_dir.to_string()));
+    }
+
+    // Overrides exist for exercising downloads, and must never stage firmware
+    // for other hardware
+    #[cfg(feature = "dev")]
+    {
+        if dev_override_active() {
+            return Err(ScheduleError::Refused("cannot schedule while a development override is set".to_string()));
+        }
+    }
+
+    if let Some(hypervisor) = steps.virtual_machine() {
+        return Err(ScheduleError::Refused(format!("cannot update firmware in a virtual machine ({})", hypervisor)));
+    }
+
+    let updater_file = UPDATER_FILE;
+    let firmware_file = firmware_filename(firmware_id);
+
+    if ! options.force {
+        if is_up_to_date(changelog, system) {
+            reporter.up_to_date();
+            return Ok(ScheduleOutcome::AlreadyUpToDate);
+        }
+
+        check_not_applied(manifest, system, firmware_id, reporter).map_err(ScheduleError::Refused)?;
+    }
+
+    let updater_dir = Path::new(efi_dir).join(UPDATER_DIR);
+
+    steps.check_writable(Path::new(efi_dir)).map_err(ScheduleError::EspNotWritable)?;
+
+    steps.check_no_conflicting_update(Path::new(efi_dir)).map_err(ScheduleError::ConflictingUpdate)?;
+
+    steps.unset_next_boot(reporter).map_err(ScheduleError::UnsetBoot)?;
+
+    steps.remove_dir(&updater_dir, reporter).map_err(ScheduleError::RemoveDir)?;
+
+    let updater_tmp = match steps.create_temp(efi_dir) {
+        Ok(ok) => ok,
+        Err(err) => {
+            return Err(ScheduleError::CreateTemp(io_err_str("create temporary directory", err)));
+        }
+    };
+
+    options.check_cancel()?;
+
+    reporter.update_stage(UpdateStage::Stage);
+    reporter.staging(StagingStage::ExtractingUpdater);
+    steps.extract(cache, manifest, updater_file, updater_tmp.path(), reporter).map_err(ScheduleError::ExtractUpdater)?;
+
+    options.check_cancel()?;
+
+    reporter.staging(StagingStage::ExtractingFirmware);
+    steps.extract(cache, manifest, &firmware_file, &updater_tmp.path().join("firmware"), reporter).map_err(ScheduleError::ExtractFirmware)?;
+
+    steps.validate_staged(updater_tmp.path()).map_err(ScheduleError::InvalidStaged)?;
+
+    options.check_cancel()?;
+
+    reporter.staging(StagingStage::Committing);
+    let mut staged = StagedDir(Some(updater_tmp.into_path()));
+    let updater_tmp_dir = staged.path().to_owned();
+    reporter.moving(&updater_tmp_dir, &updater_dir);
+    match steps.rename(&updater_tmp_dir, &updater_dir) {
+        Ok(()) => (),
+        Err(err) => {
+            return Err(ScheduleError::Rename(io_err_str(&format!("move {} to {}", updater_tmp_dir.display(), updater_dir.display()), err)));
+        }
+    }
+    staged.0 = Some(updater_dir.clone());
+
+    let files = match steps.list_dir(&updater_dir) {
+        Ok(ok) => ok,
+        Err(err) => {
+            return Err(ScheduleError::Other(format!("failed to list {}: {}", updater_dir.display(), err)));
+        }
+    };
+
+    let mut mirrors = Vec::new();
+    for esp in &options.mirror_esps {
+        mirrors.push(steps.mirror_staged(&updater_dir, esp, reporter).map_err(ScheduleError::Mirror)?);
+    }
+
+    options.check_cancel()?;
+
+    steps.set_next_boot(efi_dir, reporter).map_err(ScheduleError::SetBoot)?;
+
+    // Only now are the staged directories left for the updater
+    staged.commit();
+    for mirror in mirrors {
+        mirror.commit();
+    }
+
+    reporter.scheduled();
+    reporter.update_stage(UpdateStage::Reboot);
+
+    Ok(ScheduleOutcome::Scheduled(ScheduleReport {
+        firmware_id: firmware_id.clone(),
+        files,
+        boot_next: boot::BOOT_NUM,
+    }))
+}
+
+/// Checks whether an update is staged and `BootNext` points at the updater.
+/// This never touches the network and returns `false` on any uncertainty, so it
+/// is cheap enough to poll.
+pub fn is_scheduled(efi_dir: &str) -> bool {
+    Path::new(efi_dir).join(UPDATER_DIR).is_dir() && boot::next_boot() == Some(boot::BOOT_NUM)
+}
+
+/// Why an update that was scheduled before the current boot did not apply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MissedUpdateReason {
+    /// `BootNext` still points at the updater, so the firmware never booted it.
+    BootEntryNotHonored,
+    /// The firmware consumed `BootNext`, but Secure Boot is enabled, which
+    /// refuses to run the updater.
+    SecureBootBlocked,
+    /// The updater ran and left a log, which is included.
+    UpdaterErrored(String),
+    /// The update is still staged, but nothing points at a cause.
+    Unknown,
+}
+
+/// The log that the updater leaves in its directory when it fails
+const UPDATER_LOG: &str = "log.txt";
+
+// The time the system booted, in seconds since the epoch
+fn boot_time() -> Option<u64> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    stat.lines().find_map(|line| line.strip_prefix("btime "))?.trim().parse().ok()
+}
+
+/// Checks for an update that was scheduled before the current boot but is
+/// still staged, and explains why it did not apply from what it left behind.
+/// Returns `None` if no update was missed.
+pub fn diagnose_missed_update(efi_dir: &str) -> Result<Option<MissedUpdateReason>, String> {
+    let updater_dir = Path::new(efi_dir).join(UPDATER_DIR);
+    if ! updater_dir.is_dir() {
+        return Ok(None);
+    }
+
+    let residue = MissedUpdateResidue {
+        updater_dir: &updater_dir,
+        history: &history::update_history()?,
+        boot_time: boot_time(),
+        next_boot: boot::next_boot(),
+        secure_boot: UefiEnvironment::detect(efi_dir).secure_boot,
+    };
+    Ok(residue.diagnose())
+}
+
+// What an update that did not apply can leave behind, read once so that it can
+// be diagnosed from a synthetic state
+struct MissedUpdateResidue<'a> {
+    updater_dir: &'a Path,
+    history: &'a [HistoryEntry],
+    boot_time: Option<u64>,
+    next_boot: Option<u16>,
+    secure_boot: Option<bool>,
+}
+
+impl<'a> MissedUpdateResidue<'a> {
+    fn diagnose(&self) -> Option<MissedUpdateReason> {
+        if ! self.updater_dir.is_dir() {
+            return None;
+        }
+
+        // An update scheduled during this boot has only not been rebooted into yet
+        let last = self.history.iter().rev().find(|entry| entry.outcome == HistoryOutcome::Scheduled);
+        if let (Some(entry), Some(boot_time)) = (last, self.boot_time) {
+            if entry.time >= boot_time {
+                return None;
+            }
+        }
+
+        if let Ok(log) = fs::read_to_string(self.updater_dir.join(UPDATER_LOG)) {
+            return Some(MissedUpdateReason::UpdaterErrored(log.trim().to_string()));
+        }
+
+        if self.next_boot == Some(boot::BOOT_NUM) {
+            return Some(MissedUpdateReason::BootEntryNotHonored);
+        }
+
+        if self.secure_boot == Some(true) {
+            return Some(MissedUpdateReason::SecureBootBlocked);
+        }
+
+        Some(MissedUpdateReason::Unknown)
+    }
+}
+
+pub fn unschedule(efi_dir: &str) -> Result<(), String> {
+    unschedule_with_reporter(efi_dir, &StderrReporter)
+}
+
+/// Like `unschedule()`, sending progress to `reporter` instead of stderr.
+pub fn unschedule_with_reporter(efi_dir: &str, reporter: &dyn StatusReporter) -> Result<(), String> {
+    let _lock = lock::ScheduleLock::acquire()?;
+
+    let updater_dir = Path::new(efi_dir).join(UPDATER_DIR);
+
+    boot::unset_next_boot(reporter)?;
+
+    remove_dir(&updater_dir, reporter)?;
+
+    // efibootmgr failing to write the variable is not always reflected in its
+    // exit status, so read it back. The updater is already removed, so the
+    // firmware falls back to the next boot entry instead of flashing.
+    if boot::next_boot() == Some(boot::BOOT_NUM) {
+        return Err(format!(
+            "BootNext still points at boot entry {:04X} after unscheduling",
+            boot::BOOT_NUM
+        ));
+    }
+
+    reporter.unscheduled();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::panic;
+    use tempdir::TempDir;
+
+    fn manifest(files: &[&str]) -> Manifest {
+        Manifest {
+            time: 0,
+            files: files.iter().map(|file| (file.to_string(), String::new())).collect(),
+        }
+    }
+
+    #[test]
+    fn verify_updater_rejects_corrupt_updater() {
+        let digest = Sha384::new(&b"updater"[..]).unwrap().to_base32();
+        assert!(verify_updater(&b"updater"[..], &digest).is_ok());
+
+        let err = verify_updater(&b"corrupt updater"[..], &digest).unwrap_err();
+        assert!(err.starts_with("updater digest mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn redact_key_and_home() {
+        assert_eq!(
+            redact(&format!("bad signature from {}", config::KEY)),
+            "bad signature from <key>"
+        );
+        assert_eq!(
+            redact("failed to read /home/alice/cert.pem and /home/bob"),
+            "failed to read /home/<user>/cert.pem and /home/<user>"
+        );
+        assert_eq!(redact("failed to read /var/cache"), "failed to read /var/cache");
+    }
+
+    #[test]
+    fn bundle_revision_hash_covers_names_and_digests() {
+        let mut manifest = Manifest {
+            time: 0,
+            files: vec![
+                (UPDATER_FILE.to_string(), "DEF".to_string()),
+                ("galp3.tar.xz".to_string(), "ABC".to_string()),
+            ].into_iter().collect(),
+        };
+        let hash = bundle_revision_hash(&manifest);
+        assert_eq!(hash, "344f57525bcdee3563267636131c158415073349dc8975f181a8a76c08f7cc2f");
+
+        // The time a manifest was built is not part of the revision
+        manifest.time = 1;
+        assert_eq!(bundle_revision_hash(&manifest), hash);
+
+        manifest.files.insert("galp3.tar.xz".to_string(), "ABD".to_string());
+        assert_ne!(bundle_revision_hash(&manifest), hash);
+    }
+
+    #[test]
+    fn is_up_to_date_unreadable_versions() {
+        let changelog = changelog::Changelog {
+            versions: vec![changelog::Version {
+                bios: "1.0".to_string(),
+                description: None,
+                me: Some("2.0".to_string()),
+                requires_battery_reset: false,
+            }],
+        };
+        let mut system = SystemInfo {
+            bios: Ok(("galp3".to_string(), "1.0".to_string())),
+            ec: Err("no EC".to_string()),
+            me: Ok(Some("2.0".to_string())),
+        };
+        assert!(is_up_to_date(&changelog, &system));
+
+        system.me = Err("failed to read ME version".to_string());
+        assert!(! is_up_to_date(&changelog, &system));
+
+        system.bios = Err("failed to read BIOS version".to_string());
+        assert!(! is_up_to_date(&changelog, &system));
+    }
+
+    #[test]
+    fn model_support_current_and_legacy() {
+        let db = ModelDatabase::parse(r#"{
+            "galp2": { "display_name": "Galago Pro", "legacy": "2019-01-01" },
+            "galp3": { "display_name": "Galago Pro" }
+        }"#).unwrap();
+
+        assert_eq!(model_support_from("galp3", &db), ModelSupport::Supported);
+        assert_eq!(
+            model_support_from("galp2", &db),
+            ModelSupport::SupportedLegacy { last_firmware: "2019-01-01".to_string() }
+        );
+        assert_eq!(model_support_from("galp1", &db), ModelSupport::NotWhitelisted);
+    }
+
+    #[test]
+    fn model_support_published_uses_whitelist() {
+        let hash = "0".repeat(64);
+        let manifest = manifest(&[&format!("galp3_{}.tar.xz", hash)]);
+        let db = ModelDatabase::parse(r#"{ "oryp4": { "display_name": "Oryx Pro" } }"#).unwrap();
+
+        assert_eq!(model_support_published("galp3", &db, &manifest), ModelSupport::NotWhitelisted);
+        assert_eq!(model_support_published("oryp4", &db, &manifest), ModelSupport::NotPublished);
+    }
+
+    #[test]
+    fn model_support_published_firmware() {
+        let hash = "0".repeat(64);
+        let manifest = manifest(&[
+            UPDATER_FILE,
+            &format!("galp3_{}.tar.xz", hash),
+            &format!("galp3-c_{}.tar.xz", hash),
+        ]);
+
+        assert_eq!(model_support_published("galp3", &CompiledWhitelist, &manifest), ModelSupport::Supported);
+        assert_eq!(model_support_published("galp3-c", &CompiledWhitelist, &manifest), ModelSupport::Supported);
+        assert_eq!(model_support_published("galp3-b", &CompiledWhitelist, &manifest), ModelSupport::NotPublished);
+        assert_eq!(model_support_published("galp2", &CompiledWhitelist, &manifest), ModelSupport::NotPublished);
+        assert_eq!(model_support_published("galp1", &CompiledWhitelist, &manifest), ModelSupport::NotWhitelisted);
+    }
+
+    #[test]
+    fn remove_dir_handles_files_and_symlinks() {
+        let tmp = TempDir::new_in(env::temp_dir(), "remove_dir").unwrap();
+        let path = tmp.path().join(UPDATER_DIR);
+
+        fs::write(&path, b"left by a broken run").unwrap();
+        remove_dir(&path, &StderrReporter).unwrap();
+        assert!(fs::symlink_metadata(&path).is_err());
+
+        // Only the link is removed, never what it points at
+        let target = tmp.path().join("target");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("file"), b"kept").unwrap();
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        remove_dir(&path, &StderrReporter).unwrap();
+        assert!(fs::symlink_metadata(&path).is_err());
+        assert!(target.join("file").is_file());
+
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("boot.efi"), b"staged").unwrap();
+        remove_dir(&path, &StderrReporter).unwrap();
+        assert!(fs::symlink_metadata(&path).is_err());
+
+        // Nothing at the path is not an error
+        remove_dir(&path, &StderrReporter).unwrap();
+    }
+
+    #[test]
+    fn check_writable_rejects_read_only_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new_in(env::temp_dir(), "check_writable").unwrap();
+        check_writable(tmp.path()).unwrap();
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 0);
+
+        assert!(check_writable(&tmp.path().join("missing")).is_err());
+
+        // Root writes regardless of permissions, so only a user can see this
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+
+        let read_only = tmp.path().join("read-only");
+        fs::create_dir(&read_only).unwrap();
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555)).unwrap();
+        let err = check_writable(&read_only).unwrap_err();
+        assert!(err.contains("insufficient privileges"), "{}", err);
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn fwupd_capsules_detected_on_esp() {
+        let tmp = TempDir::new_in(env::temp_dir(), "fwupd").unwrap();
+        assert!(fwupd_capsules(tmp.path()).is_empty());
+        check_no_conflicting_update(tmp.path()).unwrap();
+
+        let fw = tmp.path().join("EFI").join("ubuntu").join("fw");
+        fs::create_dir_all(&fw).unwrap();
+        fs::write(fw.join("fwupd-ddc0ee61.cap.tmp"), b"").unwrap();
+        fs::create_dir_all(tmp.path().join("EFI").join("BOOT")).unwrap();
+        assert!(fwupd_capsules(tmp.path()).is_empty());
+
+        let capsule = fw.join("fwupd-ddc0ee61-e7f0-4e7d-acc5-c070a398838e.cap");
+        fs::write(&capsule, b"capsule").unwrap();
+        assert_eq!(fwupd_capsules(tmp.path()), vec![capsule.clone()]);
+        let err = check_no_conflicting_update(tmp.path()).unwrap_err();
+        assert!(err.contains(&capsule.display().to_string()), "{}", err);
+    }
+
+    #[test]
+    fn diagnose_missed_update_reasons() {
+        let tmp = TempDir::new_in(env::temp_dir(), "missed").unwrap();
+        let updater_dir = tmp.path().join(UPDATER_DIR);
+        let mut scheduled = HistoryEntry::new(
+            "galp3_id".to_string(),
+            "digest".to_string(),
+            ComponentVersions::default(),
+            ComponentVersions::default(),
+            HistoryOutcome::Scheduled,
+        );
+        scheduled.time = 1000;
+        let history = vec![scheduled];
+        let residue = |boot_time, next_boot, secure_boot| MissedUpdateResidue {
+            updater_dir: &updater_dir,
+            history: &history,
+            boot_time: Some(boot_time),
+            next_boot,
+            secure_boot,
+        };
+
+        // Nothing is staged, so nothing was missed
+        assert_eq!(residue(2000, Some(boot::BOOT_NUM), None).diagnose(), None);
+
+        fs::create_dir(&updater_dir).unwrap();
+        // Scheduled after booting, so not rebooted into yet
+        assert_eq!(residue(500, Some(boot::BOOT_NUM), None).diagnose(), None);
+
+        assert_eq!(residue(2000, None, None).diagnose(), Some(MissedUpdateReason::Unknown));
+        assert_eq!(residue(2000, None, Some(true)).diagnose(), Some(MissedUpdateReason::SecureBootBlocked));
+        assert_eq!(residue(2000, Some(boot::BOOT_NUM), Some(true)).diagnose(), Some(MissedUpdateReason::BootEntryNotHonored));
+
+        fs::write(updater_dir.join(UPDATER_LOG), "flash failed\n").unwrap();
+        assert_eq!(
+            residue(2000, Some(boot::BOOT_NUM), Some(true)).diagnose(),
+            Some(MissedUpdateReason::UpdaterErrored("flash failed".to_string()))
+        );
+    }
+
+    #[derive(Default)]
+    struct Infos(RefCell<Vec<String>>);
+
+    impl StatusReporter for Infos {
+        fn info(&self, message: &str) {
+            self.0.borrow_mut().push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn channel_selects_branch() {
+        assert_eq!(Channel::default().branch(), config::BRANCH);
+        assert_eq!(Channel::Stable.to_string(), format!("stable ({})", config::BRANCH));
+
+        let testing = Channel::Branch("testing".to_string());
+        assert_eq!(testing.branch(), "testing");
+        assert_eq!(testing.to_string(), "branch testing");
+
+        // The selected channel is reported before anything is fetched, here
+        // failing on a certificate that does not exist
+        let infos = Infos::default();
+        let options = DownloadOptions {
+            channel: testing,
+            cert: Some(PathBuf::from("/nonexistent/cert.pem")),
+            ..DownloadOptions::default()
+        };
+        let err = download_report(&FirmwareId::new("galp3", "none").unwrap(), &options, &infos).unwrap_err();
+        assert!(err.contains("failed to read certificate"), "{}", err);
+        assert_eq!(*infos.0.borrow(), vec!["using channel branch testing".to_string()]);
+    }
+
+    #[test]
+    fn ess_skus_resolve_their_own_firmware() {
+        let hash = ec_project_hash("none");
+        let db = ModelDatabase::parse(r#"{
+            "oryp3": { "display_name": "Oryx Pro", "quirks": ["base-only"] },
+            "oryp3-ess": { "display_name": "Oryx Pro" }
+        }"#).unwrap();
+        assert!(db.quirks("oryp3-ess").is_empty());
+
+        for &(ess, base) in &[("oryp2-ess", "oryp2"), ("oryp3-ess", "oryp3")] {
+            let id = FirmwareId::new(ess, "none").unwrap();
+            assert_eq!(id.model(), ess);
+            assert_eq!(firmware_filename(&id), format!("{}_{}.tar.xz", ess, hash));
+            assert!(model_is_whitelisted(ess), "{}", ess);
+
+            // The base SKU's firmware being published does not make the -ess
+            // SKU's available
+            let base_firmware = format!("{}_{}.tar.xz", base, hash);
+            let ess_firmware = format!("{}_{}.tar.xz", ess, hash);
+            let base_only = manifest(&[UPDATER_FILE, &base_firmware]);
+            assert_eq!(model_support_published(ess, &CompiledWhitelist, &base_only), ModelSupport::NotPublished);
+            let ess_only = manifest(&[UPDATER_FILE, &ess_firmware]);
+            assert_eq!(model_support_published(ess, &CompiledWhitelist, &ess_only), ModelSupport::Supported);
+            assert_eq!(model_support_published(base, &CompiledWhitelist, &ess_only), ModelSupport::NotPublished);
+        }
+    }
+
+    // The steps that `FailingSteps` can fail at, in the order scheduling takes them
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Step {
+        Lock,
+        OpenCache,
+        Booted,
+        Efivars,
+        EspMount,
+        VirtualMachine,
+        Writable,
+        Conflict,
+        UnsetBoot,
+        RemoveDir,
+        CreateTemp,
+        ExtractUpdater,
+        ExtractFirmware,
+        Validate,
+        Rename,
+        ListDir,
+        Mirror,
+        SetBoot,
+    }
+
+    // Schedules onto a temporary ESP with the machine mocked out, failing at
+    // one step. Steps on the ESP run for real unless they are the one to fail.
+    struct FailingSteps {
+        fail: Option<Step>,
+        dir: TempDir,
+        // Set once the firmware is extracted, as a signal handler would
+        cancel_after_extract: Option<Arc<AtomicBool>>,
+        // Panic rather than return an error at the failing step
+        panic: bool,
+        boot_next_set: Cell<bool>,
+    }
+
+    impl FailingSteps {
+        fn new(fail: Option<Step>) -> FailingSteps {
+            let dir = TempDir::new_in(env::temp_dir(), "schedule").unwrap();
+            fs::create_dir(dir.path().join("esp")).unwrap();
+            FailingSteps { fail, dir, cancel_after_extract: None, panic: false, boot_next_set: Cell::new(false) }
+        }
+
+        fn efi_dir(&self) -> String {
+            self.dir.path().join("esp").to_str().unwrap().to_string()
+        }
+
+        fn check(&self, step: Step) -> Result<(), String> {
+            if self.fail == Some(step) {
+                if self.panic {
+                    panic!("injected panic at {:?}", step);
+                }
+                return Err(format!("injected failure at {:?}", step));
+            }
+            Ok(())
+        }
+
+        fn check_io(&self, step: Step) -> io::Result<()> {
+            self.check(step).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+        }
+
+        // Lists what scheduling left on the ESP
+        fn leftovers(&self) -> Vec<PathBuf> {
+            let mut paths: Vec<PathBuf> = fs::read_dir(self.efi_dir()).unwrap().map(|entry| entry.unwrap().path()).collect();
+            paths.sort();
+            paths
+        }
+    }
+
+    impl ScheduleSteps for FailingSteps {
+        fn lock(&self) -> Result<lock::ScheduleLock, String> {
+            let lock = lock::ScheduleLock::acquire_in(self.dir.path())?;
+            if self.fail == Some(Step::Lock) {
+                return lock::ScheduleLock::acquire_in(self.dir.path());
+            }
+            Ok(lock)
+        }
+
+        fn open_cache(&self) -> Result<download::Cache, String> {
+            self.check(Step::OpenCache)?;
+            download::Cache::new(self.dir.path().join("cache"), None)
+        }
+
+        fn system(&self) -> SystemInfo {
+            SystemInfo {
+                bios: Err("mocked".to_string()),
+                ec: Err("mocked".to_string()),
+                me: Err("mocked".to_string()),
+            }
+        }
+
+        fn record(&self, _entry: HistoryEntry, _reporter: &dyn StatusReporter) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn environment(&self, _efi_dir: &str) -> boot::UefiEnvironment {
+            boot::UefiEnvironment {
+                booted_uefi: self.fail != Some(Step::Booted),
+                efivars_writable: Some(self.fail != Some(Step::Efivars)),
+                secure_boot: None,
+                esp_mount: if self.fail == Some(Step::EspMount) { None } else { Some(PathBuf::from("/dev/sda1")) },
+            }
+        }
+
+        fn virtual_machine(&self) -> Option<&'static str> {
+            if self.fail == Some(Step::VirtualMachine) { Some("KVM") } else { None }
+        }
+
+        fn check_writable(&self, dir: &Path) -> Result<(), String> {
+            self.check(Step::Writable)?;
+            check_writable(dir)
+        }
+
+        fn check_no_conflicting_update(&self, efi_dir: &Path) -> Result<(), String> {
+            self.check(Step::Conflict)?;
+            check_no_conflicting_update(efi_dir)
+        }
+
+        fn unset_next_boot(&self, _reporter: &dyn StatusReporter) -> Result<(), String> {
+            self.check(Step::UnsetBoot)
+        }
+
+        fn remove_dir(&self, path: &Path, reporter: &dyn StatusReporter) -> Result<(), String> {
+            self.check(Step::RemoveDir)?;
+            remove_dir(path, reporter)
+        }
+
+        fn create_temp(&self, efi_dir: &str) -> io::Result<TempDir> {
+            self.check_io(Step::CreateTemp)?;
+            TempDir::new_in(efi_dir, UPDATER_DIR)
+        }
+
+        fn extract(&self, _cache: &download::Cache, _manifest: &Manifest, file: &str, path: &Path, _reporter: &dyn StatusReporter) -> Result<(), String> {
+            self.check(if file == UPDATER_FILE { Step::ExtractUpdater } else { Step::ExtractFirmware })?;
+            fs::create_dir_all(path).map_err(err_str)?;
+            if file != UPDATER_FILE {
+                if let Some(ref cancel) = self.cancel_after_extract {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            }
+            Ok(())
+        }
+
+        fn validate_staged(&self, _path: &Path) -> Result<(), String> {
+            self.check(Step::Validate)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.check_io(Step::Rename)?;
+            fs::rename(from, to)
+        }
+
+        fn list_dir(&self, path: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+            self.check_io(Step::ListDir)?;
+            util::list_dir(path)
+        }
+
+        fn mirror_staged(&self, _updater_dir: &Path, _esp: &Path, _reporter: &dyn StatusReporter) -> Result<StagedDir, String> {
+            self.check(Step::Mirror)?;
+            Ok(StagedDir(None))
+        }
+
+        fn set_next_boot(&self, _efi_dir: &str, _reporter: &dyn StatusReporter) -> Result<(), String> {
+            self.check(Step::SetBoot)?;
+            self.boot_next_set.set(true);
+            Ok(())
+        }
+    }
+
+    fn schedule_options() -> ScheduleOptions {
+        ScheduleOptions {
+            force: true,
+            mirror_esps: vec![PathBuf::from("/boot/efi2")],
+            ..ScheduleOptions::default()
+        }
+    }
+
+    // Runs the staging steps, past loading the manifest
+    fn schedule_failing(steps: &FailingSteps, options: &ScheduleOptions) -> Result<ScheduleOutcome, ScheduleError> {
+        let cache = download::Cache::new(steps.dir.path().join("cache"), None).unwrap();
+        let changelog = changelog::Changelog { versions: Vec::new() };
+        let firmware_id = FirmwareId::new("galp3", "none").unwrap();
+        schedule_inner(steps, &cache, &manifest(&[]), &changelog, &steps.system(), &steps.efi_dir(), &firmware_id, options, &StderrReporter)
+    }
+
+    // Whether an error is the variant that a step reports
+    type IsVariant = fn(&ScheduleError) -> bool;
+
+    #[test]
+    fn schedule_errors_name_the_failed_step() {
+        let cases: &[(Step, IsVariant)] = &[
+            (Step::Booted, |err| matches!(err, ScheduleError::NotUefi)),
+            (Step::Efivars, |err| matches!(err, ScheduleError::EfivarsReadOnly)),
+            (Step::EspMount, |err| matches!(err, ScheduleError::EspNotMounted(_))),
+            (Step::VirtualMachine, |err| matches!(err, ScheduleError::Refused(_))),
+            (Step::Writable, |err| matches!(err, ScheduleError::EspNotWritable(_))),
+            (Step::Conflict, |err| matches!(err, ScheduleError::ConflictingUpdate(_))),
+            (Step::UnsetBoot, |err| matches!(err, ScheduleError::UnsetBoot(_))),
+            (Step::RemoveDir, |err| matches!(err, ScheduleError::RemoveDir(_))),
+            (Step::CreateTemp, |err| matches!(err, ScheduleError::CreateTemp(_))),
+            (Step::ExtractUpdater, |err| matches!(err, ScheduleError::ExtractUpdater(_))),
+            (Step::ExtractFirmware, |err| matches!(err, ScheduleError::ExtractFirmware(_))),
+            (Step::Validate, |err| matches!(err, ScheduleError::InvalidStaged(_))),
+            (Step::Rename, |err| matches!(err, ScheduleError::Rename(_))),
+            (Step::ListDir, |err| matches!(err, ScheduleError::Other(_))),
+            (Step::Mirror, |err| matches!(err, ScheduleError::Mirror(_))),
+            (Step::SetBoot, |err| matches!(err, ScheduleError::SetBoot(_))),
+        ];
+
+        for &(step, expected) in cases {
+            let steps = FailingSteps::new(Some(step));
+            match schedule_failing(&steps, &schedule_options()) {
+                Ok(_) => panic!("scheduling did not fail at {:?}", step),
+                Err(err) => assert!(expected(&err), "{:?} failed with {:?}", step, err),
+            }
+            assert_eq!(steps.leftovers(), Vec::<PathBuf>::new(), "{:?} left files staged", step);
+        }
+    }
+
+    #[test]
+    fn schedule_errors_before_staging() {
+        let steps = FailingSteps::new(Some(Step::Lock));
+        let err = schedule_steps(&steps, "digest", &steps.efi_dir(), &FirmwareId::new("galp3", "none").unwrap(), &schedule_options(), &StderrReporter).unwrap_err();
+        assert!(matches!(err, ScheduleError::AlreadyRunning(_)), "{:?}", err);
+
+        let steps = FailingSteps::new(Some(Step::OpenCache));
+        let err = schedule_steps(&steps, "digest", &steps.efi_dir(), &FirmwareId::new("galp3", "none").unwrap(), &schedule_options(), &StderrReporter).unwrap_err();
+        assert!(matches!(err, ScheduleError::LoadManifest(_)), "{:?}", err);
+
+        // A digest that is not in the cache fails to load as well
+        let steps = FailingSteps::new(None);
+        let err = schedule_steps(&steps, "digest", &steps.efi_dir(), &FirmwareId::new("galp3", "none").unwrap(), &schedule_options(), &StderrReporter).unwrap_err();
+        assert!(matches!(err, ScheduleError::LoadManifest(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn schedule_cancelled() {
+        let steps = FailingSteps::new(None);
+        let options = ScheduleOptions {
+            cancel: Some(Arc::new(AtomicBool::new(true))),
+            ..schedule_options()
+        };
+        let err = schedule_failing(&steps, &options).unwrap_err();
+        assert!(matches!(err, ScheduleError::Cancelled), "{:?}", err);
+        assert_eq!(steps.leftovers(), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn schedule_cancelled_between_extract_and_rename() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut steps = FailingSteps::new(None);
+        steps.cancel_after_extract = Some(cancel.clone());
+        let options = ScheduleOptions {
+            cancel: Some(cancel),
+            ..schedule_options()
+        };
+
+        let err = schedule_failing(&steps, &options).unwrap_err();
+        assert!(matches!(err, ScheduleError::Cancelled), "{:?}", err);
+        assert_eq!(steps.leftovers(), Vec::<PathBuf>::new());
+        assert!(! steps.boot_next_set.get());
+    }
+
+    #[test]
+    fn staged_dir_removed_unless_committed() {
+        let tmp = TempDir::new_in(env::temp_dir(), "staged").unwrap();
+        let path = tmp.path().join(UPDATER_DIR);
+
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("boot.efi"), b"staged").unwrap();
+        drop(StagedDir(Some(path.clone())));
+        assert!(! path.exists());
+
+        fs::create_dir(&path).unwrap();
+        StagedDir(Some(path.clone())).commit();
+        assert!(path.is_dir());
+    }
+
+    #[test]
+    fn schedule_panic_after_staging_leaves_nothing() {
+        for &step in &[Step::Rename, Step::ListDir, Step::Mirror, Step::SetBoot] {
+            let mut steps = FailingSteps::new(Some(step));
+            steps.panic = true;
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                schedule_failing(&steps, &schedule_options())
+            }));
+            assert!(result.is_err(), "{:?} did not panic", step);
+            assert_eq!(steps.leftovers(), Vec::<PathBuf>::new(), "{:?} left files staged", step);
+        }
+    }
+
+    #[test]
+    fn schedule_without_failures() {
+        let steps = FailingSteps::new(None);
+        match schedule_failing(&steps, &schedule_options()) {
+            Ok(ScheduleOutcome::Scheduled(report)) => assert_eq!(report.boot_next, boot::BOOT_NUM),
+            other => panic!("scheduling failed: {:?}", other),
+        }
+        assert!(steps.boot_next_set.get());
+        assert_eq!(steps.leftovers(), vec![Path::new(&steps.efi_dir()).join(UPDATER_DIR)]);
+    }
 }
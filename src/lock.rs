@@ -0,0 +1,71 @@
+use libc;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use config;
+
+/// The lock file, in the cache directory
+const LOCK_FILE: &str = "schedule.lock";
+
+/// Held while the ESP is being changed, so that scheduling and unscheduling in
+/// different processes cannot interleave. The lock belongs to the open file,
+/// so it is released when this is dropped, including when unwinding from a
+/// panic, and by the kernel if the process dies.
+pub struct ScheduleLock(File);
+
+impl ScheduleLock {
+    pub fn acquire() -> Result<ScheduleLock, String> {
+        ScheduleLock::acquire_in(Path::new(config::CACHE))
+    }
+
+    /// Takes the lock file in `dir`, creating the directory if needed.
+    pub fn acquire_in(dir: &Path) -> Result<ScheduleLock, String> {
+        // Unscheduling may come before anything was ever downloaded
+        fs::create_dir_all(dir).map_err(|err| {
+            format!("failed to create {}: {}", dir.display(), err)
+        })?;
+
+        let path = dir.join(LOCK_FILE);
+        let file = OpenOptions::new().create(true).write(true).truncate(false).open(&path).map_err(|err| {
+            format!("failed to open {}: {}", path.display(), err)
+        })?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Err("another process is already scheduling or cancelling a firmware update".to_string());
+            }
+            return Err(format!("failed to lock {}: {}", path.display(), err));
+        }
+
+        Ok(ScheduleLock(file))
+    }
+}
+
+impl Drop for ScheduleLock {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tempdir::TempDir;
+
+    #[test]
+    fn second_lock_fails_while_first_is_held() -> Result<(), String> {
+        let tmp = TempDir::new_in(env::temp_dir(), "lock").map_err(|err| err.to_string())?;
+
+        let first = ScheduleLock::acquire_in(tmp.path())?;
+        let err = ScheduleLock::acquire_in(tmp.path()).err().expect("second lock was taken");
+        assert!(err.contains("already scheduling"), "{}", err);
+
+        drop(first);
+        ScheduleLock::acquire_in(tmp.path())?;
+        Ok(())
+    }
+}
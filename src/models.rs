@@ -0,0 +1,183 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::ptr;
+use std::sync::Once;
+
+use whitelist::WhitelistSource;
+
+/// The model database compiled into the crate
+const MODELS_JSON: &str = include_str!("models.json");
+
+static EMBEDDED_INIT: Once = Once::new();
+static mut EMBEDDED: Option<Result<ModelDatabase, String>> = None;
+
+/// What is known about a model. A field left out for a model is unknown,
+/// rather than false.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ModelInfo {
+    /// The name the model is sold under.
+    pub display_name: String,
+    /// Whether the model has an EC that the firmware updates. Desktops have
+    /// none.
+    pub has_ec: Option<bool>,
+    /// Whether the model has an Intel ME. Models with AMD processors have
+    /// none.
+    pub has_me: Option<bool>,
+    /// Names of model specific behavior needed when updating.
+    #[serde(default)]
+    pub quirks: Vec<String>,
+    /// The last firmware version published, once System76 stops publishing new
+    /// firmware for the model.
+    pub legacy: Option<String>,
+}
+
+impl ModelInfo {
+    /// Checks whether the model needs the named quirk.
+    pub fn has_quirk(&self, quirk: &str) -> bool {
+        self.quirks.iter().any(|x| x == quirk)
+    }
+}
+
+/// The models that firmware updates are enabled for, keyed by the model as
+/// reported in the DMI `product_version`. A suffix such as `-b` or `-c` names
+/// a revision of the board, and `-ess` a special SKU, with its own firmware,
+/// so each is listed separately.
+#[derive(Clone, Debug, Default)]
+pub struct ModelDatabase {
+    models: BTreeMap<String, ModelInfo>,
+}
+
+impl ModelDatabase {
+    /// Parses a database in the format of the embedded `models.json`.
+    pub fn parse(json: &str) -> Result<ModelDatabase, String> {
+        let models = serde_json::from_str(json).map_err(|err| {
+            format!("failed to parse model database: {}", err)
+        })?;
+        Ok(ModelDatabase { models })
+    }
+
+    /// The database compiled into the crate, parsed on first use. The tests
+    /// parse it, so an error here is a bug in the crate.
+    pub fn embedded() -> Result<&'static ModelDatabase, String> {
+        EMBEDDED_INIT.call_once(|| {
+            let result = ModelDatabase::parse(MODELS_JSON).map_err(|err| format!("embedded {}", err));
+            // Only written here, and `call_once` orders this before every read
+            unsafe { EMBEDDED = Some(result) };
+        });
+        match unsafe { &*ptr::addr_of!(EMBEDDED) } {
+            Some(Ok(db)) => Ok(db),
+            Some(Err(err)) => Err(err.clone()),
+            None => Err("embedded model database was not initialized".to_string()),
+        }
+    }
+
+    /// Reads a database from a file. As with `FileWhitelist`, the embedded
+    /// database is used instead if the file cannot be read or parsed.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ModelDatabase, String> {
+        let result = fs::read_to_string(path.as_ref())
+            .map_err(|err| err.to_string())
+            .and_then(|json| ModelDatabase::parse(&json));
+
+        match result {
+            Ok(ok) => Ok(ok),
+            Err(err) => {
+                status!("ignoring {}: {}", path.as_ref().display(), err);
+                Ok(ModelDatabase::embedded()?.clone())
+            }
+        }
+    }
+
+    pub fn get(&self, model: &str) -> Option<&ModelInfo> {
+        self.models.get(model)
+    }
+
+    /// The quirks of a model, none if it is not in the database.
+    pub fn quirks(&self, model: &str) -> &[String] {
+        self.get(model).map_or(&[], |info| info.quirks.as_slice())
+    }
+
+    /// The last firmware version of a model that no longer receives updates.
+    pub fn legacy(&self, model: &str) -> Option<&str> {
+        self.get(model).and_then(|info| info.legacy.as_deref())
+    }
+
+    /// Every model in the database, sorted.
+    pub fn models(&self) -> impl Iterator<Item = &str> {
+        self.models.keys().map(|model| model.as_str())
+    }
+}
+
+impl WhitelistSource for ModelDatabase {
+    fn contains(&self, model: &str) -> bool {
+        self.models.contains_key(model)
+    }
+
+    fn legacy(&self, model: &str) -> Option<String> {
+        ModelDatabase::legacy(self, model).map(String::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The models of the whitelist that the database replaced
+    const OLD_WHITELIST: &[&str] = &[
+        "addw1", "bonw11", "bonw12", "bonw13", "darp5", "darp6", "galp2",
+        "galp3", "galp3-b", "galp3-c", "galp4", "gaze10", "gaze11", "gaze12",
+        "gaze13", "gaze14", "kudu2", "kudu3", "kudu4", "kudu5", "lemu6",
+        "lemu7", "lemu8", "meer4", "orxp1", "oryp2", "oryp2-ess", "oryp3",
+        "oryp3-b", "oryp3-ess", "oryp4", "oryp4-b", "oryp5", "serw9", "serw10",
+        "serw11", "serw11-b", "thelio-b1", "thelio-major-b1",
+        "thelio-major-b1.1", "thelio-major-b2", "thelio-major-r1",
+        "thelio-major-r2", "thelio-mega-b1", "thelio-mega-r1", "thelio-r1",
+    ];
+
+    #[test]
+    fn embedded_parses() {
+        ModelDatabase::parse(MODELS_JSON).unwrap();
+    }
+
+    #[test]
+    fn embedded_covers_old_whitelist() {
+        let db = ModelDatabase::embedded().unwrap();
+        for model in OLD_WHITELIST {
+            assert!(db.contains(model), "{} is missing", model);
+        }
+        assert_eq!(db.models().count(), OLD_WHITELIST.len());
+    }
+
+    #[test]
+    fn embedded_components() {
+        let db = ModelDatabase::embedded().unwrap();
+        assert_eq!(db.get("galp3").and_then(|x| x.has_ec), Some(true));
+        assert_eq!(db.get("thelio-r1").and_then(|x| x.has_ec), Some(false));
+        assert_eq!(db.get("thelio-r1").and_then(|x| x.has_me), Some(false));
+        assert_eq!(db.get("thelio-b1").and_then(|x| x.has_me), Some(true));
+    }
+
+    #[test]
+    fn parse_fields() {
+        let json = r#"{
+            "galp2": { "display_name": "Galago Pro", "has_ec": true, "quirks": ["slow-esp"], "legacy": "1.0" },
+            "galp3": { "display_name": "Galago Pro" }
+        }"#;
+        let db = ModelDatabase::parse(json).unwrap();
+        assert_eq!(db.quirks("galp2"), ["slow-esp".to_string()]);
+        assert!(db.get("galp2").unwrap().has_quirk("slow-esp"));
+        assert_eq!(db.legacy("galp2"), Some("1.0"));
+        assert_eq!(db.get("galp3").unwrap().has_ec, None);
+        assert!(db.quirks("galp3").is_empty());
+        assert!(db.quirks("galp1").is_empty());
+        assert_eq!(db.legacy("galp3"), None);
+    }
+
+    #[test]
+    fn parse_rejects_unknown_fields() {
+        let json = r#"{ "galp3": { "display_name": "Galago Pro", "cpu": "intel" } }"#;
+        assert!(ModelDatabase::parse(json).is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+fn read_file<P: AsRef<Path>>(path: P) -> Option<String> {
+    fs::read_to_string(path).ok().map(|x| x.trim().to_string())
+}
+
+// Finds the power supplies of a type, such as `Mains` or `Battery`
+fn supplies(kind: &str) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Ok(entries) = fs::read_dir(POWER_SUPPLY) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if read_file(entry.path().join("type")).as_deref() == Some(kind) {
+                paths.push(entry.path());
+            }
+        }
+    }
+    paths.sort();
+    paths
+}
+
+/// Checks whether AC power is connected, or `None` if there is no AC adapter
+/// to ask, as on a desktop.
+pub fn ac_online() -> Option<bool> {
+    let mains = supplies("Mains");
+    if mains.is_empty() {
+        return None;
+    }
+
+    Some(mains.iter().any(|path| {
+        read_file(path.join("online")).as_deref() == Some("1")
+    }))
+}
+
+/// Reads the charge of the first battery in percent, or `None` if there is no
+/// battery.
+pub fn battery_capacity() -> Option<u8> {
+    supplies("Battery").iter().filter_map(|path| {
+        read_file(path.join("capacity")).and_then(|x| x.parse::<u8>().ok())
+    }).next()
+}
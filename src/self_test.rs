@@ -0,0 +1,95 @@
+use libc;
+use std::ffi::CString;
+use std::{fs, io, mem};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use {bios, check_writable, config, current_model, downloader, ec, efivars_writable, model_info, redact, Channel, UefiEnvironment};
+
+/// The outcome of one check of `self_test()`.
+#[derive(Clone, Debug)]
+pub struct SelfTestResult {
+    /// What was checked.
+    pub name: &'static str,
+    /// What was found, or why the check failed.
+    pub result: Result<String, String>,
+}
+
+// The space available to unprivileged users on the filesystem of a path
+fn free_space(path: &Path) -> Result<u64, String> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|err| err.to_string())?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!("failed to stat {}: {}", path.display(), io::Error::last_os_error()));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+fn check_network() -> Result<String, String> {
+    let channel = Channel::Stable;
+    let tail = downloader(&channel, None)?.tail().map_err(|err| redact(&err))?;
+    Ok(format!("{} is reachable, latest manifest {} on channel {}", config::URL, tail.digest, channel))
+}
+
+fn check_esp(efi_dir: &str) -> Result<String, String> {
+    let device = UefiEnvironment::detect(efi_dir).esp_mount.ok_or_else(|| {
+        format!("{} is not a mount point", efi_dir)
+    })?;
+    check_writable(Path::new(efi_dir))?;
+    let free = free_space(Path::new(efi_dir))?;
+    Ok(format!("{} is mounted at {}, {} MiB free", device.display(), efi_dir, free / 1024 / 1024))
+}
+
+// Desktops have no EC to read, which is no problem for updating them
+fn check_ec() -> Result<String, String> {
+    let model = current_model()?;
+    if model_info(&model).and_then(|info| info.has_ec) == Some(false) {
+        return Ok(format!("{} has no EC", model));
+    }
+    ec(true).map(|(project, version)| format!("{} {}", project, version))
+}
+
+fn check_cache() -> Result<String, String> {
+    let path = Path::new(config::CACHE);
+    if ! path.is_dir() {
+        fs::create_dir_all(path).map_err(|err| format!("failed to create {}: {}", path.display(), err))?;
+    }
+    check_writable(path)?;
+    Ok(format!("{} is writable", path.display()))
+}
+
+/// Checks everything that downloading and scheduling depend on, each check
+/// independently, so that one report shows every problem at once. This uses
+/// the network, and most checks require root.
+pub fn self_test(efi_dir: &str) -> Vec<SelfTestResult> {
+    vec![
+        SelfTestResult {
+            name: "network",
+            result: check_network(),
+        },
+        SelfTestResult {
+            name: "bios",
+            result: bios().map(|(model, version)| format!("{} {}", model, version)),
+        },
+        SelfTestResult {
+            name: "ec",
+            result: check_ec(),
+        },
+        SelfTestResult {
+            name: "efivars",
+            result: if efivars_writable() {
+                Ok("writable".to_string())
+            } else {
+                Err("EFI variables cannot be written".to_string())
+            },
+        },
+        SelfTestResult {
+            name: "esp",
+            result: check_esp(efi_dir),
+        },
+        SelfTestResult {
+            name: "cache",
+            result: check_cache(),
+        },
+    ]
+}
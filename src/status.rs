@@ -0,0 +1,124 @@
+use std::path::Path;
+
+/// The steps of staging an update on the ESP, reported in this order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StagingStage {
+    /// The updater is being extracted to a temporary directory.
+    ExtractingUpdater,
+    /// The firmware is being extracted next to the updater.
+    ExtractingFirmware,
+    /// The staged directory is being moved into place and the boot entry set.
+    Committing,
+}
+
+/// The steps of a whole update, for a front-end to render as a checklist.
+/// Downloading and scheduling report the steps up to `Reboot`. The later steps
+/// happen in the updater after the reboot, so they are never reported by the
+/// crate, and are for a service that checks the result after booting to mark.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UpdateStage {
+    /// The manifest and bundles are being downloaded.
+    Download,
+    /// The downloaded updater is being verified.
+    Verify,
+    /// The update is being staged on the ESP.
+    Stage,
+    /// The update is scheduled, and waits for a reboot.
+    Reboot,
+    /// The updater is flashing the firmware.
+    Flash,
+    /// The installed firmware is being checked against the update.
+    VerifyInstalled,
+}
+
+impl UpdateStage {
+    /// Every stage, in the order that an update goes through them.
+    pub const ALL: &'static [UpdateStage] = &[
+        UpdateStage::Download,
+        UpdateStage::Verify,
+        UpdateStage::Stage,
+        UpdateStage::Reboot,
+        UpdateStage::Flash,
+        UpdateStage::VerifyInstalled,
+    ];
+}
+
+/// Receives the user-facing progress of downloading and scheduling, so that an
+/// embedder such as a GUI can present it natively. Every method defaults to
+/// printing what the command line tool does, so a reporter only needs to
+/// override the events it presents itself.
+pub trait StatusReporter {
+    /// A file of the manifest is being downloaded.
+    fn downloading(&self, file: &str) {
+        status!("downloading {}", file);
+    }
+
+    /// A bundle is being extracted to the ESP.
+    fn extracting(&self, file: &str, path: &Path) {
+        status!("extracting {} to {}", file, path.display());
+    }
+
+    /// An entry of a bundle was unpacked.
+    fn extracted(&self, path: &Path) {
+        status_out!("{:?}", path);
+    }
+
+    /// The update moved on to the next stage. The stages are already described
+    /// by the other events, so nothing is printed by default.
+    fn update_stage(&self, _stage: UpdateStage) {}
+
+    /// Staging moved on to the next step. The steps are already described by
+    /// the other events, so nothing is printed by default.
+    fn staging(&self, _stage: StagingStage) {}
+
+    /// A previously staged directory is being removed.
+    fn removing(&self, path: &Path) {
+        status!("removing {}", path.display());
+    }
+
+    /// The staged updater is being moved into place.
+    fn moving(&self, from: &Path, to: &Path) {
+        status!("moving {} to {}", from.display(), to.display());
+    }
+
+    /// The installed firmware already matches the bundle.
+    fn up_to_date(&self) {
+        status!("Firmware is already up to date.");
+    }
+
+    /// The update was staged and will be installed on the next boot.
+    fn scheduled(&self) {
+        status!("Firmware update scheduled. Reboot your machine to install.");
+    }
+
+    /// A staged update was cancelled.
+    fn unscheduled(&self) {
+        status!("Firmware update cancelled.");
+    }
+
+    /// Any other progress message.
+    fn info(&self, message: &str) {
+        status!("{}", message);
+    }
+
+    /// A problem that does not fail the operation.
+    fn warning(&self, message: &str) {
+        status!("{}", message);
+    }
+
+    /// Asked between objects while downloading and between entries while
+    /// extracting. While it returns `true`, the work is paused, so that a
+    /// background service can yield to the desktop. Nothing is throttled by
+    /// default.
+    fn throttle(&self) -> bool {
+        false
+    }
+}
+
+/// The default reporter, printing every event to stderr.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrReporter;
+
+impl StatusReporter for StderrReporter {}
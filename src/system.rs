@@ -0,0 +1,40 @@
+use {bios, ec, me, model_info, ComponentVersions};
+
+/// A snapshot of the installed firmware, read once so that every step of an
+/// operation sees the same values without querying the hardware again. The
+/// versions change after flashing, so a new snapshot must be read then.
+#[derive(Clone, Debug)]
+pub struct SystemInfo {
+    /// The BIOS model and version, or the error from reading them.
+    pub bios: Result<(String, String), String>,
+    /// The EC project and version, or the error from reading them.
+    pub ec: Result<(String, String), String>,
+    /// The ME version if there is an ME, or the error from reading it.
+    pub me: Result<Option<String>, String>,
+}
+
+impl SystemInfo {
+    /// Reads each source once. The ME is not read on models that the model
+    /// database records as having none.
+    pub fn read() -> SystemInfo {
+        let bios = bios();
+        let has_me = bios.as_ref().ok()
+            .and_then(|(model, _)| model_info(model))
+            .and_then(|info| info.has_me);
+
+        SystemInfo {
+            bios,
+            ec: ec(true),
+            me: if has_me == Some(false) { Ok(None) } else { me() },
+        }
+    }
+
+    /// The installed versions, leaving out any that could not be read.
+    pub fn versions(&self) -> ComponentVersions {
+        ComponentVersions {
+            bios: self.bios.as_ref().ok().map(|(_, version)| version.clone()),
+            ec: self.ec.as_ref().ok().map(|(_, version)| version.clone()),
+            me: self.me.clone().ok().and_then(|x| x),
+        }
+    }
+}
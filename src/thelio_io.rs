@@ -5,7 +5,7 @@ use std::collections::HashMap;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use {config, download, err_str};
+use {config, err_str, open_cache, redact};
 
 fn read_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
     fs::read_to_string(path).map(|x| x.trim().to_string())
@@ -159,19 +159,19 @@ pub fn thelio_io_download() -> Result<(String, String), String> {
         config::THELIO_IO_PROJECT,
         config::BRANCH,
         Some(config::CERT)
-    )?;
+    ).map_err(|err| redact(&err))?;
 
-    let tail = dl.tail()?;
+    let tail = dl.tail().map_err(|err| redact(&err))?;
 
-    let cache = download::Cache::new(config::CACHE, Some(dl))?;
+    let cache = open_cache(Some(dl))?;
 
-    eprintln!("downloading manifest.json");
+    status!("downloading manifest.json");
     let manifest_json = cache.object(&tail.digest)?;
     let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(|e| e.to_string())?;
 
     let metadata_json = {
         let file = "metadata.json";
-        eprintln!("downloading {}", file);
+        status!("downloading {}", file);
         let digest = manifest.files.get(file).ok_or(format!("{} not found", file))?;
         cache.object(&digest)?
     };
@@ -181,7 +181,7 @@ pub fn thelio_io_download() -> Result<(String, String), String> {
 
     let _firmware_data = {
         let file = "main.hex";
-        eprintln!("downloading {}", file);
+        status!("downloading {}", file);
         let digest = manifest.files.get(file).ok_or(format!("{} not found", file))?;
         cache.object(&digest)?
     };
@@ -207,8 +207,23 @@ pub fn thelio_io_list() -> Result<HashMap<String, String>, String> {
     Ok(map)
 }
 
-pub fn thelio_io_update(digest: &str) -> Result<(), String> {
-    let cache = download::Cache::new(config::CACHE, None)?;
+/// What `thelio_io_update` flashed. Boards change paths when switching to the
+/// bootloader, so revisions are reported for the update as a whole.
+#[derive(Clone, Debug)]
+pub struct ThelioIoUpdateResult {
+    /// The device the firmware is for.
+    pub device: String,
+    /// The revision the out of date boards had before flashing, if any were
+    /// found outside of the bootloader.
+    pub from_version: Option<String>,
+    /// The revision that was flashed.
+    pub to_version: String,
+    /// The number of boards flashed, which is zero if all were up to date.
+    pub flashed: usize,
+}
+
+pub fn thelio_io_update(digest: &str) -> Result<ThelioIoUpdateResult, String> {
+    let cache = open_cache(None)?;
 
     let manifest_json = cache.object(&digest)?;
     let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(err_str)?;
@@ -228,68 +243,79 @@ pub fn thelio_io_update(digest: &str) -> Result<(), String> {
         cache.object(&digest)?
     };
 
-    eprintln!("Switching devices to bootloader");
+    status!("Switching devices to bootloader");
+    let mut from_version = None;
+    let mut flashed = 0;
     let mut sleep = false;
     for thelio_io in ThelioIo::all().map_err(err_str)? {
-        eprintln!(" {:?}", thelio_io.path());
+        status!(" {:?}", thelio_io.path());
         match thelio_io {
             ThelioIo::Bootloader(_) => {
-                eprintln!("  already in bootloader");
+                status!("  already in bootloader");
             },
             ThelioIo::Normal(normal) => {
                 let revision = normal.revision().unwrap_or(String::new());
-                eprintln!("  revision: {:?}", revision);
+                status!("  revision: {:?}", revision);
                 if revision != metadata.revision {
-                    eprintln!("  switching to bootloader");
+                    if from_version.is_none() {
+                        from_version = Some(revision);
+                    }
+                    status!("  switching to bootloader");
                     normal.bootloader().map_err(err_str)?;
                     sleep = true;
                 } else {
-                    eprintln!("  already up to date");
+                    status!("  already up to date");
                 }
             },
         }
     }
 
     if sleep {
-        eprintln!("Waiting 5 seconds");
+        status!("Waiting 5 seconds");
         thread::sleep(time::Duration::new(5, 0));
     }
 
-    eprintln!("Flashing devices");
+    status!("Flashing devices");
     sleep = false;
     for thelio_io in ThelioIo::all().map_err(err_str)? {
-        eprintln!(" {:?}", thelio_io.path());
+        status!(" {:?}", thelio_io.path());
         match thelio_io {
             ThelioIo::Bootloader(bootloader) => {
-                eprintln!("  flashing: {}", metadata.revision);
+                status!("  flashing: {}", metadata.revision);
                 bootloader.flash(&firmware_data).map_err(err_str)?;
                 bootloader.reset().map_err(err_str)?;
+                flashed += 1;
                 sleep = true;
             },
             ThelioIo::Normal(_) => {
-                eprintln!("  not in bootloader!");
+                status!("  not in bootloader!");
             }
         }
     }
 
     if sleep {
-        eprintln!("Waiting 5 seconds");
+        status!("Waiting 5 seconds");
         thread::sleep(time::Duration::new(5, 0));
     }
 
-    eprintln!("Enumerating devices");
+    status!("Enumerating devices");
     for thelio_io in ThelioIo::all().map_err(err_str)? {
-        eprintln!(" {:?}", thelio_io.path());
+        status!(" {:?}", thelio_io.path());
         match thelio_io {
             ThelioIo::Bootloader(_) => {
-                eprintln!("  still in bootloader!");
+                status!("  still in bootloader!");
             },
             ThelioIo::Normal(normal) => {
                 let revision = normal.revision().unwrap_or(String::new());
-                eprintln!("  revision: {:?}", revision);
+                status!("  revision: {:?}", revision);
             }
         }
     }
 
-    Ok(())
+    Ok(ThelioIoUpdateResult {
+        device: metadata.device,
+        from_version,
+        to_version: metadata.revision,
+        flashed,
+    })
 }
@@ -0,0 +1,53 @@
+use std::fs;
+
+/// PCR banks exposed by the kernel for TPM 2.0, preferred in this order
+const PCR_BANKS: &[&str] = &["sha256", "sha1"];
+
+/// The PCR list exposed by the kernel for TPM 1.2
+const PCRS_LEGACY: &str = "/sys/class/tpm/tpm0/device/pcrs";
+
+// Parses the `PCR-00: XX XX ...` line of a TPM 1.2 PCR list
+fn parse_legacy_pcr0(data: &str) -> Option<String> {
+    let line = data.lines().find_map(|line| line.strip_prefix("PCR-00:"))?;
+    let value = line.split_whitespace().collect::<String>();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_lowercase())
+    }
+}
+
+/// Reads PCR 0, which measures the system firmware, as lowercase hex. This is
+/// informational, for seeing the measurement change after an update, and is
+/// `None` on machines without a TPM.
+pub fn pcr0() -> Option<String> {
+    for bank in PCR_BANKS {
+        let path = format!("/sys/class/tpm/tpm0/pcr-{}/0", bank);
+        if let Ok(value) = fs::read_to_string(&path) {
+            let value = value.trim();
+            if ! value.is_empty() {
+                return Some(value.to_lowercase());
+            }
+        }
+    }
+
+    fs::read_to_string(PCRS_LEGACY).ok().and_then(|data| parse_legacy_pcr0(&data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_legacy_pcr0_line() {
+        let data = "PCR-00: 3A 3F 78 0F 11 A4 B4 99\nPCR-01: 00 00 00 00 00 00 00 00\n";
+        assert_eq!(parse_legacy_pcr0(data), Some("3a3f780f11a4b499".to_string()));
+    }
+
+    #[test]
+    fn parse_legacy_pcr0_missing() {
+        assert_eq!(parse_legacy_pcr0("PCR-01: 00 00\n"), None);
+        assert_eq!(parse_legacy_pcr0("PCR-00:\n"), None);
+        assert_eq!(parse_legacy_pcr0(""), None);
+    }
+}
@@ -1,20 +1,38 @@
 use lzma::reader::LzmaReader;
-use std::{fs, io, path};
+use std::{borrow, fs, io, path};
 use std::io::Read;
 use sha2::{Sha256, Digest};
 use tar::Archive;
 
-pub fn extract<P: AsRef<path::Path>>(data: &[u8], p: P) -> io::Result<()> {
-    let decompressor = LzmaReader::new_decompressor(data).map_err(|err| io::Error::new(
+use {StatusReporter, StderrReporter};
+
+pub fn extract<R: Read, P: AsRef<path::Path>>(reader: R, p: P) -> io::Result<()> {
+    extract_with_progress(reader, p, &StderrReporter, |entry_path| StderrReporter.extracted(entry_path))
+}
+
+/// Like `extract`, calling `progress` with the path of each entry as it is
+/// unpacked, and warning `reporter` of entries that are skipped
+pub fn extract_with_progress<R: Read, P: AsRef<path::Path>, F: FnMut(&path::Path)>(reader: R, p: P, reporter: &dyn StatusReporter, mut progress: F) -> io::Result<()> {
+    let decompressor = LzmaReader::new_decompressor(reader).map_err(|err| io::Error::new(
         io::ErrorKind::Other,
         err
     ))?;
     let mut tar = Archive::new(decompressor);
+    // The ESP is FAT, which cannot represent unix permission bits
+    tar.set_preserve_permissions(false);
 
     for file_res in tar.entries()?{
         let mut file = file_res?;
 
-        println!("{:?}", file.path());
+        // FAT cannot hold symlinks, hard links, or device and fifo nodes, none of
+        // which the updater needs, so they are skipped rather than failing
+        let entry_type = file.header().entry_type();
+        if ! entry_type.is_file() && ! entry_type.is_dir() {
+            reporter.warning(&format!("skipping {:?}: unsupported entry type {:?}", file.path(), entry_type));
+            continue;
+        }
+
+        progress(&file.path()?);
         if ! file.unpack_in(&p)? {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -26,8 +44,8 @@ pub fn extract<P: AsRef<path::Path>>(data: &[u8], p: P) -> io::Result<()> {
     Ok(())
 }
 
-pub fn extract_file<P: AsRef<path::Path>>(data: &[u8], path: P) -> io::Result<String> {
-    let decompressor = LzmaReader::new_decompressor(data).map_err(|err| io::Error::new(
+pub fn extract_file<R: Read, P: AsRef<path::Path>>(reader: R, path: P) -> io::Result<String> {
+    let decompressor = LzmaReader::new_decompressor(reader).map_err(|err| io::Error::new(
         io::ErrorKind::Other,
         err
     ))?;
@@ -42,7 +60,6 @@ pub fn extract_file<P: AsRef<path::Path>>(data: &[u8], path: P) -> io::Result<St
             }
         }
 
-        println!("{:?}", file.path());
         let mut s = String::new();
         file.read_to_string(&mut s)?;
         return Ok(s);
@@ -54,6 +71,28 @@ pub fn extract_file<P: AsRef<path::Path>>(data: &[u8], path: P) -> io::Result<St
     ))
 }
 
+/// Lists the regular files in a compressed tar archive, relative to its root
+pub fn list_files<R: Read>(reader: R) -> io::Result<Vec<path::PathBuf>> {
+    let decompressor = LzmaReader::new_decompressor(reader).map_err(|err| io::Error::new(
+        io::ErrorKind::Other,
+        err
+    ))?;
+    let mut tar = Archive::new(decompressor);
+
+    let mut files = Vec::new();
+    for file_res in tar.entries()?{
+        let file = file_res?;
+        if file.header().entry_type().is_file() {
+            let file_path = file.path()?;
+            let relative = file_path.strip_prefix(".").unwrap_or(&file_path);
+            files.push(relative.to_owned());
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
 pub fn read_string<P: AsRef<path::Path>>(p: P) -> io::Result<String> {
     let mut string = String::new();
     {
@@ -63,6 +102,124 @@ pub fn read_string<P: AsRef<path::Path>>(p: P) -> io::Result<String> {
     Ok(string)
 }
 
+/// Reads a DMI string. Trailing NULs are stripped and bytes that are not UTF-8
+/// are replaced rather than failing, so a slightly malformed DMI table still
+/// yields a usable value.
+pub fn read_dmi<P: AsRef<path::Path>>(p: P) -> io::Result<String> {
+    let data = fs::read(p.as_ref())?;
+
+    let mut end = data.len();
+    while end > 0 && data[end - 1] == 0 {
+        end -= 1;
+    }
+
+    let string = String::from_utf8_lossy(&data[..end]);
+    if let borrow::Cow::Owned(_) = string {
+        status!("{} is not valid UTF-8: {:?}", p.as_ref().display(), string);
+    }
+
+    Ok(string.trim().trim_matches('\0').to_string())
+}
+
+/// Recursively lists the files under a directory with their sizes, sorted by path
+pub fn list_dir<P: AsRef<path::Path>>(p: P) -> io::Result<Vec<(path::PathBuf, u64)>> {
+    let mut files = Vec::new();
+    for entry_res in fs::read_dir(p)? {
+        let entry = entry_res?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            files.extend(list_dir(entry.path())?);
+        } else {
+            files.push((entry.path(), metadata.len()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Recursively copies the contents of a directory into an existing directory
+pub fn copy_dir<P: AsRef<path::Path>, Q: AsRef<path::Path>>(src: P, dst: Q) -> io::Result<()> {
+    for entry_res in fs::read_dir(src)? {
+        let entry = entry_res?;
+        let dst_path = dst.as_ref().join(entry.file_name());
+        if entry.metadata()?.is_dir() {
+            fs::create_dir(&dst_path)?;
+            copy_dir(entry.path(), &dst_path)?;
+        } else {
+            fs::copy(entry.path(), &dst_path)?;
+        }
+    }
+    Ok(())
+}
+
 pub fn sha256(input: &[u8]) -> String {
     format!("{:x}", Sha256::digest(input))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use tar::{Builder, EntryType, Header};
+    use tempdir::TempDir;
+
+    #[derive(Default)]
+    struct Warnings(RefCell<Vec<String>>);
+
+    impl StatusReporter for Warnings {
+        fn warning(&self, message: &str) {
+            self.0.borrow_mut().push(message.to_string());
+        }
+    }
+
+    // Builds an xz tarball of regular files followed by symlinks
+    fn tarball(files: &[(&str, &[u8])], symlinks: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for &(path, data) in files {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            builder.append_data(&mut header, path, data).unwrap();
+        }
+        for &(path, target) in symlinks {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            header.set_link_name(target).unwrap();
+            builder.append_data(&mut header, path, io::empty()).unwrap();
+        }
+        lzma::compress(&builder.into_inner().unwrap(), 6).unwrap()
+    }
+
+    #[test]
+    fn extract_skips_symlinks() {
+        let dir = TempDir::new_in(env::temp_dir(), "extract").unwrap();
+        let data = tarball(&[("boot.efi", b"loader")], &[("link.efi", "boot.efi")]);
+
+        let warnings = Warnings::default();
+        let mut extracted = Vec::new();
+        extract_with_progress(&data[..], dir.path(), &warnings, |path| extracted.push(path.to_owned())).unwrap();
+
+        assert_eq!(extracted, vec![path::PathBuf::from("boot.efi")]);
+        assert_eq!(fs::read(dir.path().join("boot.efi")).unwrap(), b"loader");
+        assert!(fs::symlink_metadata(dir.path().join("link.efi")).is_err());
+        assert_eq!(warnings.0.borrow().len(), 1);
+        assert!(warnings.0.borrow()[0].contains("link.efi"), "{:?}", warnings.0.borrow());
+    }
+
+    #[test]
+    fn read_dmi_strips_nuls_and_replaces_invalid_utf8() {
+        let dir = TempDir::new_in(env::temp_dir(), "read_dmi").unwrap();
+
+        let path = dir.path().join("product_version");
+        fs::write(&path, b"galp3-c\n\0\0").unwrap();
+        assert_eq!(read_dmi(&path).unwrap(), "galp3-c");
+
+        fs::write(&path, b"galp\xff3\n").unwrap();
+        assert_eq!(read_dmi(&path).unwrap(), "galp\u{FFFD}3");
+
+        assert!(read_dmi(dir.path().join("missing")).is_err());
+    }
+}
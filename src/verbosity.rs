@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How much the crate prints on its own, for embedders that present progress
+/// themselves or want clean output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum Verbosity {
+    /// Nothing is printed.
+    Silent,
+    /// Progress is printed, the default.
+    Normal,
+    /// Progress is printed, along with details such as cache hits.
+    Verbose,
+}
+
+static VERBOSITY: AtomicUsize = AtomicUsize::new(1);
+
+/// Sets the verbosity for the whole process.
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as usize, Ordering::SeqCst);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::SeqCst) {
+        0 => Verbosity::Silent,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+// Prints to stderr unless the verbosity is silent
+macro_rules! status {
+    ($($arg:tt)*) => (
+        if $crate::verbosity() >= $crate::Verbosity::Normal {
+            eprintln!($($arg)*);
+        }
+    )
+}
+
+// Prints to stdout unless the verbosity is silent
+macro_rules! status_out {
+    ($($arg:tt)*) => (
+        if $crate::verbosity() >= $crate::Verbosity::Normal {
+            println!($($arg)*);
+        }
+    )
+}
+
+// Prints to stderr only when the verbosity is verbose
+macro_rules! verbose {
+    ($($arg:tt)*) => (
+        if $crate::verbosity() >= $crate::Verbosity::Verbose {
+            eprintln!($($arg)*);
+        }
+    )
+}
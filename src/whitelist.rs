@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::Path;
+
+use models::ModelDatabase;
+
+/// Where the list of models that firmware updates are enabled for comes from.
+pub trait WhitelistSource {
+    /// Checks a model against the list exactly, as `model_is_whitelisted` does.
+    fn contains(&self, model: &str) -> bool;
+
+    /// The last firmware version of a model that no longer receives updates.
+    /// Defaults to what the embedded model database records.
+    fn legacy(&self, model: &str) -> Option<String> {
+        ModelDatabase::embedded().ok()?.legacy(model).map(String::from)
+    }
+}
+
+/// The models of the model database compiled into the crate, the default
+/// source.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompiledWhitelist;
+
+impl WhitelistSource for CompiledWhitelist {
+    fn contains(&self, model: &str) -> bool {
+        // A database that fails to parse enables no model, rather than the
+        // wrong ones
+        ModelDatabase::embedded().map_or(false, |db| db.contains(model))
+    }
+}
+
+/// A whitelist read from a file with one model per line. Blank lines and lines
+/// starting with `#` are ignored. If the file cannot be read or any line is
+/// malformed, the compiled whitelist is used instead, so that a broken file
+/// never enables updates for the wrong models.
+#[derive(Clone, Debug)]
+pub struct FileWhitelist {
+    models: Option<Vec<String>>,
+}
+
+impl FileWhitelist {
+    pub fn load<P: AsRef<Path>>(path: P) -> FileWhitelist {
+        let models = match fs::read_to_string(path.as_ref()) {
+            Ok(data) => parse(&data).map_err(|err| {
+                status!("ignoring {}: {}", path.as_ref().display(), err);
+            }).ok(),
+            Err(err) => {
+                status!("ignoring {}: {}", path.as_ref().display(), err);
+                None
+            }
+        };
+
+        FileWhitelist { models }
+    }
+}
+
+impl WhitelistSource for FileWhitelist {
+    fn contains(&self, model: &str) -> bool {
+        match self.models {
+            Some(ref models) => models.iter().any(|x| x == model),
+            None => CompiledWhitelist.contains(model),
+        }
+    }
+}
+
+fn parse(data: &str) -> Result<Vec<String>, String> {
+    let mut models = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.contains(char::is_whitespace) {
+            return Err(format!("line {} is not a single model: {:?}", i + 1, line));
+        }
+
+        models.push(line.to_string());
+    }
+    Ok(models)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tempdir::TempDir;
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let data = "# Enabled models\ngalp3\n\n  galp3-c  \n# oryp4\n";
+        assert_eq!(parse(data).unwrap(), vec!["galp3", "galp3-c"]);
+    }
+
+    #[test]
+    fn parse_rejects_malformed_lines() {
+        let err = parse("galp3\ngalp3 oryp4\n").unwrap_err();
+        assert!(err.starts_with("line 2 "), "{}", err);
+    }
+
+    #[test]
+    fn sources_membership() {
+        assert!(CompiledWhitelist.contains("galp3"));
+        assert!(! CompiledWhitelist.contains("galp3-z"));
+
+        let file = FileWhitelist { models: Some(vec!["galp3-z".to_string()]) };
+        assert!(file.contains("galp3-z"));
+        assert!(! file.contains("galp3"));
+    }
+
+    #[test]
+    fn revision_suffixes_match_exactly() {
+        let models: Vec<&str> = ModelDatabase::embedded().unwrap().models().collect();
+        let variants: Vec<&str> = models.iter().cloned().filter(|model| model.contains('-')).collect();
+        assert!(variants.contains(&"galp3-c"), "{:?}", variants);
+
+        // A whitelist of one model matches that model and nothing else, so a
+        // revision never matches its base model or a sibling, nor the reverse
+        for &listed in &models {
+            let file = FileWhitelist { models: Some(vec![listed.to_string()]) };
+            for &model in &models {
+                assert_eq!(file.contains(model), model == listed, "{} listed, {} checked", listed, model);
+            }
+        }
+
+        for &variant in &variants {
+            assert!(CompiledWhitelist.contains(variant), "{}", variant);
+            assert!(! CompiledWhitelist.contains(&variant.to_uppercase()), "{}", variant);
+            assert!(! CompiledWhitelist.contains(&format!("{}-z", variant)), "{}", variant);
+        }
+    }
+
+    #[test]
+    fn malformed_file_degrades_to_compiled() {
+        let dir = TempDir::new_in(env::temp_dir(), "whitelist").unwrap();
+        let path = dir.path().join("whitelist");
+        fs::write(&path, "galp3-z\ngalp3 oryp4\n").unwrap();
+
+        let file = FileWhitelist::load(&path);
+        assert!(file.contains("galp3"));
+        assert!(! file.contains("galp3-z"));
+
+        let missing = FileWhitelist::load(dir.path().join("missing"));
+        assert!(missing.contains("galp3"));
+    }
+}
@@ -0,0 +1,20 @@
+extern crate system76_firmware;
+
+use system76_firmware::download::ObjectError;
+
+// ObjectError is non-exhaustive, so code outside the crate must be able to
+// match it with a wildcard arm for variants added later
+fn describe(err: &ObjectError) -> &'static str {
+    match err {
+        ObjectError::Download(_) => "download",
+        ObjectError::Io(_) => "io",
+        ObjectError::NotCached(_) => "not cached",
+        _ => "other",
+    }
+}
+
+#[test]
+fn wildcard_match() {
+    assert_eq!(describe(&ObjectError::NotCached("object".to_string())), "not cached");
+    assert_eq!(describe(&ObjectError::Download("failed".to_string())), "download");
+}